@@ -0,0 +1,64 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+#[cfg(test)]
+use tokio::sync::watch;
+
+/// Where a [`Node`](crate::Node) takes the time from. Timers, liveness and the event loop's
+/// sleeps all go through it, so tests can swap in a [`MockClock`] and step time by hand.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Resolves once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The wall clock, sleeping on tokio's timer.
+#[derive(Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves on [`MockClock::advance`]. Sleeps resolve once it has been
+/// advanced past their deadline.
+#[cfg(test)]
+pub struct MockClock {
+    now: watch::Sender<Instant>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: watch::Sender::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let deadline = self.now() + duration;
+        let mut now = self.now.subscribe();
+
+        Box::pin(async move {
+            let _ = now.wait_for(|now| *now >= deadline).await;
+        })
+    }
+}
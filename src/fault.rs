@@ -0,0 +1,151 @@
+use std::fmt::Debug;
+
+use crate::{Message, Payload};
+
+/// Drops, delays and duplicates outgoing messages, to see how workloads cope with a lossy
+/// network without running Maelstrom. Every decision comes from a generator seeded up
+/// front, so a failing run replays the same way.
+///
+/// A delayed message is held back until a number of later messages went out, so it arrives
+/// out of order rather than late by some amount of time.
+pub struct FaultInjector<P = Payload> {
+    /// State of the splitmix64 generator.
+    state: u64,
+    drop: f64,
+    duplicate: f64,
+    delay: f64,
+    max_delay: usize,
+    /// Delayed messages and how many more sends each waits for.
+    held: Vec<(usize, Message<P>)>,
+}
+
+impl<P: Clone + Debug> FaultInjector<P> {
+    const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+    /// Lets every message through until configured otherwise.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            drop: 0.0,
+            duplicate: 0.0,
+            delay: 0.0,
+            max_delay: 1,
+            held: Vec::new(),
+        }
+    }
+
+    /// Loses `fraction` of the messages.
+    pub fn drop(mut self, fraction: f64) -> Self {
+        self.drop = fraction;
+        self
+    }
+
+    /// Sends `fraction` of the messages twice.
+    pub fn duplicate(mut self, fraction: f64) -> Self {
+        self.duplicate = fraction;
+        self
+    }
+
+    /// Holds `fraction` of the messages back for up to `max_sends` later messages.
+    pub fn delay(mut self, fraction: f64, max_sends: usize) -> Self {
+        self.delay = fraction;
+        self.max_delay = max_sends.max(1);
+        self
+    }
+
+    /// Decides the fate of `message`, returning what goes out now: possibly nothing,
+    /// possibly it twice, and any earlier messages whose delay is up.
+    pub fn outgoing(&mut self, message: Message<P>) -> Vec<Message<P>> {
+        let mut out = Vec::new();
+
+        if self.sample() < self.drop {
+            tracing::debug!(?message, "fault: dropped");
+        } else if self.sample() < self.delay {
+            let sends = 1 + (self.sample() * self.max_delay as f64) as usize;
+            tracing::debug!(?message, sends, "fault: delayed");
+            // Counted down by this very send too.
+            self.held.push((sends + 1, message));
+        } else {
+            if self.sample() < self.duplicate {
+                tracing::debug!(?message, "fault: duplicated");
+                out.push(message.clone());
+            }
+            out.push(message);
+        }
+
+        for (sends, _) in &mut self.held {
+            *sends -= 1;
+        }
+        let (due, held) = std::mem::take(&mut self.held)
+            .into_iter()
+            .partition(|(sends, _)| *sends == 0);
+        self.held = held;
+        out.extend(due.into_iter().map(|(_, message)| message));
+
+        out
+    }
+
+    /// The next value of the generator, uniform in `[0, 1)`.
+    fn sample(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(Self::GOLDEN_GAMMA);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Body, Payload};
+
+    fn echo(msg_id: u64) -> Message {
+        Message {
+            src: "n1".to_string(),
+            dst: "n2".to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                ts: None,
+                payload: Payload::Echo {
+                    echo: "hi".to_string(),
+                },
+            },
+        }
+    }
+
+    fn msg_ids(messages: Vec<Message>) -> Vec<u64> {
+        messages.into_iter().filter_map(|m| m.body.msg_id).collect()
+    }
+
+    #[test]
+    fn faults_apply_as_configured() {
+        let mut lossy = FaultInjector::new(1).drop(1.0);
+        assert!(lossy.outgoing(echo(1)).is_empty());
+
+        let mut echoing = FaultInjector::new(1).duplicate(1.0);
+        assert_eq!(msg_ids(echoing.outgoing(echo(1))), [1, 1]);
+
+        let mut slow = FaultInjector::new(1).delay(1.0, 1);
+        assert!(slow.outgoing(echo(1)).is_empty());
+        // Held back itself, the second one lets the first one out.
+        assert_eq!(msg_ids(slow.outgoing(echo(2))), [1]);
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let run = |seed| {
+            let mut faults = FaultInjector::new(seed).drop(0.3).duplicate(0.2);
+            (0..100)
+                .flat_map(|msg_id| msg_ids(faults.outgoing(echo(msg_id))))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+}
@@ -0,0 +1,601 @@
+//! The Maelstrom wire protocol: messages, their payloads and the error codes. The storage
+//! service the kafka workload keeps its logs in lives in [`storage`], with its clients, and
+//! the merge of kafka logs that diverged between nodes in [`reconcile`].
+
+pub mod reconcile;
+pub mod storage;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+
+/// A message on the wire. Generic over the payload so a workload can bring its own payload
+/// enum; most workloads of this node share [`Payload`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Message<P = Payload> {
+    pub src: String,
+    #[serde(rename = "dest")]
+    pub dst: String,
+    pub body: Body<P>,
+}
+
+/// Who a message id names, going by Maelstrom's naming scheme.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdKind {
+    /// `c1`, `c2`, ...
+    Client,
+    /// `n0`, `n1`, ...
+    Node,
+    /// Anything else, such as the `seq-kv` service.
+    Unknown,
+}
+
+/// Tells clients from nodes by the prefix of `id`, a letter followed by a number.
+pub fn id_kind(id: &str) -> IdKind {
+    let numbered = |rest: &str| !rest.is_empty() && rest.bytes().all(|b| b.is_ascii_digit());
+
+    match id.split_at_checked(1) {
+        Some(("c", rest)) if numbered(rest) => IdKind::Client,
+        Some(("n", rest)) if numbered(rest) => IdKind::Node,
+        _ => IdKind::Unknown,
+    }
+}
+
+/// The part of a [`Message`] that is still readable when its payload is not, used to address
+/// `malformed_request` replies.
+#[derive(Deserialize)]
+pub struct Envelope {
+    pub src: String,
+    #[serde(rename = "dest")]
+    pub dst: String,
+    pub body: EnvelopeBody,
+}
+
+#[derive(Deserialize)]
+pub struct EnvelopeBody {
+    pub msg_id: Option<u64>,
+    pub in_reply_to: Option<u64>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Body<P = Payload> {
+    pub msg_id: Option<u64>,
+    pub in_reply_to: Option<u64>,
+    /// Lamport timestamp, stamped on every message this node sends. Missing counts as 0.
+    pub ts: Option<u64>,
+    #[serde(flatten)]
+    pub payload: P,
+}
+
+/// The payloads a node answers itself instead of handing them to its workload.
+#[derive(Debug, PartialEq)]
+pub enum Control<'a> {
+    Init {
+        node_id: &'a str,
+    },
+    InitOk,
+    Ping,
+    Pong,
+    /// A workload's way of saying there is nothing to send back, never sent itself.
+    DontReply,
+    /// A body whose `type` the payload enum doesn't know.
+    Unknown(&'a str),
+}
+
+/// What a node needs to know about a payload type to run a workload over it: the `init`
+/// handshake, the payloads it handles itself and how errors are told.
+pub trait NodePayload: Serialize + DeserializeOwned + Clone + Debug + Send + 'static {
+    /// Splits an `init` into the node's id and the ids of all nodes, handing any other
+    /// payload back.
+    fn into_init(self) -> Result<(String, Vec<String>), Self>;
+
+    fn init_ok() -> Self;
+
+    fn ping() -> Self;
+
+    fn pong() -> Self;
+
+    fn dont_reply() -> Self;
+
+    fn error(code: MaelstromError, text: String) -> Self;
+
+    /// The serde `type` tag of this payload.
+    fn type_name(&self) -> &'static str;
+
+    /// Whether this payload answers a request rather than being one.
+    fn is_reply(&self) -> bool;
+
+    /// Which of the node's own payloads this is, `None` for the workload's.
+    fn control(&self) -> Option<Control<'_>>;
+
+    /// The code of an `error` reply, `None` for any other payload.
+    fn error_code(&self) -> Option<&MaelstromError>;
+
+    fn is_dont_reply(&self) -> bool {
+        self.control() == Some(Control::DontReply)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+
+    /// Liveness probe between nodes, answered by the node itself.
+    Ping,
+    Pong,
+
+    Echo {
+        echo: String,
+    },
+    EchoOk {
+        echo: String,
+    },
+
+    /// Operations are `(op, key, value)` with `op` being `"r"` or `"w"`.
+    Txn {
+        txn: Vec<(String, usize, Option<usize>)>,
+    },
+    TxnOk {
+        txn: Vec<(String, usize, Option<usize>)>,
+    },
+    /// The writes of a committed transaction, with the clock it committed at.
+    TxnReplicate {
+        writes: Vec<(usize, usize)>,
+        clock: BTreeMap<String, u64>,
+    },
+
+    Broadcast {
+        message: usize,
+    },
+    BroadcastOk,
+    /// Gossiped values. `seq` numbers the batches a node sends each peer, so the peer can
+    /// apply them in order.
+    BroadcastBatch {
+        messages: Vec<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Acknowledges a whole `BroadcastBatch`.
+    BroadcastBatchOk {
+        /// How many of its values the receiver hadn't seen before.
+        fresh: usize,
+    },
+
+    /// Without a `key`, reads the broadcast values or the g-counter total, whichever
+    /// workload runs. With one it is a key-value read, as issued to `seq-kv` and served by
+    /// the `lin-kv` workload. Key-value keys are any JSON value, `lin-kv` uses integers.
+    /// The reply shape is the workload's, see [`ReadResult`].
+    Read {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<serde_json::Value>,
+    },
+    ReadOk(ReadResult),
+
+    Write {
+        key: serde_json::Value,
+        value: serde_json::Value,
+    },
+    WriteOk,
+
+    Cas {
+        key: serde_json::Value,
+        from: serde_json::Value,
+        to: serde_json::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        create_if_not_exists: Option<bool>,
+    },
+    CasOk,
+    Create {
+        key: serde_json::Value,
+        value: serde_json::Value,
+    },
+    CreateOk,
+
+    Add {
+        delta: i64,
+    },
+    AddOk,
+    /// Both halves of a PN-counter, per node. `seq` numbers the gossip a node sends each
+    /// peer, as for `BroadcastBatch`.
+    CounterGossip {
+        increments: HashMap<String, u64>,
+        decrements: HashMap<String, u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Asks a peer for its counter maps, for quorum reads.
+    CounterRead,
+    CounterReadOk {
+        increments: HashMap<String, u64>,
+        decrements: HashMap<String, u64>,
+    },
+
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+
+    Send {
+        key: String,
+        msg: usize,
+        /// Client-chosen id making retries of the same send idempotent.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dedup_id: Option<String>,
+    },
+    SendOk {
+        offset: usize,
+    },
+
+    Poll {
+        offsets: BTreeMap<String, usize>,
+        /// Caps the messages returned per key, counted from the requested offset.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_msgs: Option<usize>,
+    },
+    /// Like `Poll`, but only up to and including each key's committed offset. Keys without
+    /// one return nothing. Answered with `PollOk`.
+    PollCommitted {
+        offsets: BTreeMap<String, usize>,
+    },
+    PollOk {
+        #[serde(rename = "msgs")]
+        messages: BTreeMap<String, Vec<LogEntry>>,
+        /// Keys polled from below their oldest kept entry, with the offset they start at.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        truncated: BTreeMap<String, usize>,
+    },
+
+    CommitOffsets {
+        offsets: BTreeMap<String, usize>,
+    },
+    CommitOffsetsOk {
+        /// Keys without a log, left uncommitted.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        skipped: Vec<String>,
+    },
+
+    /// Asks the owner of the keys whether it would accept committing `offsets`, without
+    /// committing anything. Answered with `CommitOffsetsOk` naming the keys it would skip,
+    /// or with the error the commit would fail with.
+    CheckCommitOffsets {
+        offsets: BTreeMap<String, usize>,
+    },
+
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: BTreeMap<String, usize>,
+    },
+    /// Copies an entry the owner of `key` appended at `offset` to one of its replicas.
+    ReplicaAppend {
+        key: String,
+        msg: usize,
+        offset: usize,
+    },
+    ReplicaAppendOk,
+    /// Reads a replica's own copy of the logs, answered with `PollOk`. Unlike `Poll` it is
+    /// never forwarded or repaired.
+    ReplicaPoll {
+        offsets: BTreeMap<String, usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_msgs: Option<usize>,
+    },
+
+    /// Asks a kafka node for everything it stores, for test drivers chasing a failed check.
+    /// Not part of Maelstrom, so only built with the `debug_api` feature.
+    #[cfg(feature = "debug_api")]
+    DebugDump,
+    /// The logs this node owns or keeps a replica of, values in offset order.
+    #[cfg(feature = "debug_api")]
+    DebugDumpOk {
+        message_storage: HashMap<String, Vec<usize>>,
+        commit_offsets: HashMap<String, usize>,
+    },
+    /// Asks a broadcast node where each value it has seen first came from, to spot values
+    /// the topology delivers over several paths.
+    #[cfg(feature = "debug_api")]
+    DebugProvenance,
+    #[cfg(feature = "debug_api")]
+    DebugProvenanceOk {
+        provenance: BTreeMap<usize, Provenance>,
+    },
+
+    DontReply,
+
+    Error {
+        code: MaelstromError,
+        text: String,
+    },
+
+    /// Any body whose `type` isn't one of the above, kept as is so it can still be answered.
+    #[serde(untagged)]
+    Other(serde_json::Value),
+}
+
+/// What a `read_ok` carries. Untagged: a reply is told apart by its fields, so each
+/// workload's checker gets exactly the shape it expects.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ReadResult {
+    /// broadcast: every value seen.
+    Messages { messages: Vec<usize> },
+    /// g-counter: the total. Key-value: the value under the key.
+    Value { value: serde_json::Value },
+}
+
+/// Where a broadcast value was first received from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Provenance {
+    pub from: String,
+    /// The Lamport timestamp of the message that brought it.
+    pub ts: u64,
+}
+
+/// One message of a kafka log, sent as an `[offset, value]` pair.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LogEntry {
+    pub offset: usize,
+    pub value: usize,
+}
+
+impl Serialize for LogEntry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.offset, self.value).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LogEntry {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (offset, value) = <(usize, usize)>::deserialize(deserializer)?;
+
+        Ok(LogEntry { offset, value })
+    }
+}
+
+impl Payload {
+    /// The serde `type` tag of this payload.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Payload::Init { .. } => "init",
+            Payload::InitOk => "init_ok",
+            Payload::Ping => "ping",
+            Payload::Pong => "pong",
+            Payload::Echo { .. } => "echo",
+            Payload::EchoOk { .. } => "echo_ok",
+            Payload::Txn { .. } => "txn",
+            Payload::TxnOk { .. } => "txn_ok",
+            Payload::TxnReplicate { .. } => "txn_replicate",
+            Payload::Broadcast { .. } => "broadcast",
+            Payload::BroadcastOk => "broadcast_ok",
+            Payload::BroadcastBatch { .. } => "broadcast_batch",
+            Payload::BroadcastBatchOk { .. } => "broadcast_batch_ok",
+            Payload::Read { .. } => "read",
+            Payload::ReadOk(_) => "read_ok",
+            Payload::Write { .. } => "write",
+            Payload::WriteOk => "write_ok",
+            Payload::Cas { .. } => "cas",
+            Payload::CasOk => "cas_ok",
+            Payload::Create { .. } => "create",
+            Payload::CreateOk => "create_ok",
+            Payload::Add { .. } => "add",
+            Payload::AddOk => "add_ok",
+            Payload::CounterGossip { .. } => "counter_gossip",
+            Payload::CounterRead => "counter_read",
+            Payload::CounterReadOk { .. } => "counter_read_ok",
+            Payload::Topology { .. } => "topology",
+            Payload::TopologyOk => "topology_ok",
+            Payload::Send { .. } => "send",
+            Payload::SendOk { .. } => "send_ok",
+            Payload::Poll { .. } => "poll",
+            Payload::PollCommitted { .. } => "poll_committed",
+            Payload::PollOk { .. } => "poll_ok",
+            Payload::CommitOffsets { .. } => "commit_offsets",
+            Payload::CommitOffsetsOk { .. } => "commit_offsets_ok",
+            Payload::CheckCommitOffsets { .. } => "check_commit_offsets",
+            Payload::ListCommittedOffsets { .. } => "list_committed_offsets",
+            Payload::ListCommittedOffsetsOk { .. } => "list_committed_offsets_ok",
+            Payload::ReplicaAppend { .. } => "replica_append",
+            Payload::ReplicaAppendOk => "replica_append_ok",
+            Payload::ReplicaPoll { .. } => "replica_poll",
+            #[cfg(feature = "debug_api")]
+            Payload::DebugDump => "debug_dump",
+            #[cfg(feature = "debug_api")]
+            Payload::DebugDumpOk { .. } => "debug_dump_ok",
+            #[cfg(feature = "debug_api")]
+            Payload::DebugProvenance => "debug_provenance",
+            #[cfg(feature = "debug_api")]
+            Payload::DebugProvenanceOk { .. } => "debug_provenance_ok",
+            Payload::DontReply => "dont_reply",
+            Payload::Error { .. } => "error",
+            Payload::Other(..) => "other",
+        }
+    }
+
+    /// Whether this payload answers a request rather than being one.
+    pub fn is_reply(&self) -> bool {
+        #[cfg(feature = "debug_api")]
+        if let Payload::DebugDumpOk { .. } | Payload::DebugProvenanceOk { .. } = self {
+            return true;
+        }
+
+        matches!(
+            self,
+            Payload::InitOk
+                | Payload::Pong
+                | Payload::EchoOk { .. }
+                | Payload::TxnOk { .. }
+                | Payload::BroadcastOk
+                | Payload::BroadcastBatchOk { .. }
+                | Payload::ReadOk(_)
+                | Payload::WriteOk
+                | Payload::CasOk
+                | Payload::CreateOk
+                | Payload::AddOk
+                | Payload::CounterReadOk { .. }
+                | Payload::TopologyOk
+                | Payload::SendOk { .. }
+                | Payload::PollOk { .. }
+                | Payload::CommitOffsetsOk { .. }
+                | Payload::ListCommittedOffsetsOk { .. }
+                | Payload::ReplicaAppendOk
+                | Payload::Error { .. }
+        )
+    }
+}
+
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Clone)]
+#[repr(u8)]
+pub enum MaelstromError {
+    /**
+        Indicates that the requested operation could not be completed within a Timeout.
+    */
+    Timeout = 0,
+    /**
+        Thrown when a client sends an RPC request to a node which does not exist.
+    */
+    NodeNotFound = 1,
+    /**
+        Use this error to indicate that a requested operation is not supported by the current implementation. Helpful for stubbing out APIs during development.
+    */
+    NotSupported = 10,
+    /**
+    Indicates that the operation definitely cannot be performed at this time--perhaps because the server is in a read-only state, has not yet been initialized, believes its peers to be down, and so on. Do not use this error for indeterminate cases, when the operation may actually have taken place.
+    */
+    TemporarilyUnavailable = 11,
+    /**
+        The client's request did not conform to the server's expectations, and could not possibly have been processed.
+    */
+    MalformedRequest = 12,
+    /**
+        Indicates that some kind of general, indefinite error occurred. Use this as a catch-all for errors you can't otherwise categorize, or as a starting point for your error handler: it's safe to return internal-error for every problem by default, then add special cases for more specific errors later.
+    */
+    Crash = 13,
+    /**
+        Indicates that some kind of general, definite error occurred. Use this as a catch-all for errors you can't otherwise categorize, when you specifically know that the requested operation has not taken place. For instance, you might encounter an indefinite failure during the prepare phase of a transaction: since you haven't started the commit process yet, the transaction can't have taken place. It's therefore safe to return a definite abort to the client.
+    */
+    Abort = 14,
+    /**
+        The client requested an operation on a key which does not exist (assuming the operation should not automatically create missing keys).
+    */
+    KeyDoesNotExist = 20,
+    /**
+        The client requested the creation of a key which already exists, and the server will not overwrite it.
+    */
+    KeyAlreadyExists = 21,
+    /**
+        The requested operation expected some conditions to hold, and those conditions were not met. For instance, a compare-and-set operation might assert that the value of a key is currently 5; if the value is 3, the server would return precondition-failed.
+    */
+    PreconditionFailed = 22,
+    /**
+        The requested transaction has been aborted because of a conflict with another transaction. Servers need not return this error on every conflict: they may choose to retry automatically instead.
+    */
+    TxnConflict = 30,
+}
+
+impl NodePayload for Payload {
+    fn into_init(self) -> Result<(String, Vec<String>), Self> {
+        match self {
+            Payload::Init { node_id, node_ids } => Ok((node_id, node_ids)),
+            payload => Err(payload),
+        }
+    }
+
+    fn init_ok() -> Self {
+        Payload::InitOk
+    }
+
+    fn ping() -> Self {
+        Payload::Ping
+    }
+
+    fn pong() -> Self {
+        Payload::Pong
+    }
+
+    fn dont_reply() -> Self {
+        Payload::DontReply
+    }
+
+    fn error(code: MaelstromError, text: String) -> Self {
+        Payload::Error { code, text }
+    }
+
+    fn type_name(&self) -> &'static str {
+        Payload::type_name(self)
+    }
+
+    fn is_reply(&self) -> bool {
+        Payload::is_reply(self)
+    }
+
+    fn control(&self) -> Option<Control<'_>> {
+        match self {
+            Payload::Init { node_id, .. } => Some(Control::Init { node_id }),
+            Payload::InitOk => Some(Control::InitOk),
+            Payload::Ping => Some(Control::Ping),
+            Payload::Pong => Some(Control::Pong),
+            Payload::DontReply => Some(Control::DontReply),
+            Payload::Other(body) => Some(Control::Unknown(
+                body.get("type")
+                    .and_then(serde_json::Value::as_str)
+                    .unwrap_or_default(),
+            )),
+            _ => None,
+        }
+    }
+
+    fn error_code(&self) -> Option<&MaelstromError> {
+        match self {
+            Payload::Error { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_kind_follows_the_prefix() {
+        assert_eq!(id_kind("c1"), IdKind::Client);
+        assert_eq!(id_kind("c42"), IdKind::Client);
+        assert_eq!(id_kind("n0"), IdKind::Node);
+        assert_eq!(id_kind("n17"), IdKind::Node);
+        assert_eq!(id_kind("seq-kv"), IdKind::Unknown);
+        assert_eq!(id_kind("c"), IdKind::Unknown);
+        assert_eq!(id_kind("node1"), IdKind::Unknown);
+        assert_eq!(id_kind(""), IdKind::Unknown);
+    }
+
+    #[test]
+    fn read_ok_shape_picks_the_result() {
+        let broadcast: Payload =
+            serde_json::from_str(r#"{"type":"read_ok","messages":[1,2]}"#).unwrap();
+        assert!(matches!(
+            broadcast,
+            Payload::ReadOk(ReadResult::Messages { messages }) if messages == [1, 2]
+        ));
+
+        let counter: Payload = serde_json::from_str(r#"{"type":"read_ok","value":5}"#).unwrap();
+        assert!(matches!(
+            &counter,
+            Payload::ReadOk(ReadResult::Value { value }) if *value == 5
+        ));
+
+        assert_eq!(
+            serde_json::to_value(&counter).unwrap(),
+            serde_json::json!({"type": "read_ok", "value": 5})
+        );
+    }
+}
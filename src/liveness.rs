@@ -0,0 +1,91 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::workload::{Rng, Timer};
+
+/// Tracks when each peer was last heard from. Nodes ping each other on an interval, so a
+/// peer that stays quiet past the timeout is suspected to be down or partitioned away.
+pub struct Liveness {
+    last_seen: HashMap<String, Instant>,
+    timeout: Duration,
+    ping: Timer,
+}
+
+impl Liveness {
+    pub fn new(ping_interval: Duration, timeout: Duration) -> Self {
+        Self {
+            last_seen: HashMap::new(),
+            timeout,
+            ping: Timer::new(ping_interval),
+        }
+    }
+
+    /// Starts tracking `peers`, giving each a full timeout from `now` before it can be
+    /// suspected.
+    pub fn track<'a>(&mut self, peers: impl IntoIterator<Item = &'a String>, now: Instant) {
+        self.last_seen = peers.into_iter().map(|peer| (peer.clone(), now)).collect();
+    }
+
+    /// Records a message from `peer`. Sources that aren't tracked peers are ignored.
+    pub fn seen(&mut self, peer: &str, now: Instant) {
+        if let Some(last_seen) = self.last_seen.get_mut(peer) {
+            *last_seen = now;
+        }
+    }
+
+    pub fn is_suspect(&self, peer: &str, now: Instant) -> bool {
+        self.last_seen
+            .get(peer)
+            .is_some_and(|last_seen| now - *last_seen > self.timeout)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &str> {
+        self.last_seen.keys().map(String::as_str)
+    }
+
+    pub fn next_ping(&self, now: Instant) -> Duration {
+        self.ping.remaining(now)
+    }
+
+    /// Returns `true` when it's time to ping the peers again.
+    pub fn ping_due(&mut self, now: Instant, rng: &mut Rng) -> bool {
+        self.ping.fire(now, rng)
+    }
+
+    pub fn log(&self, now: Instant) {
+        let liveness: BTreeMap<_, _> = self
+            .last_seen
+            .iter()
+            .map(|(peer, last_seen)| {
+                (
+                    peer.as_str(),
+                    ((now - *last_seen).as_millis(), self.is_suspect(peer, now)),
+                )
+            })
+            .collect();
+
+        tracing::debug!(?liveness, "peer liveness as (ms since last seen, suspect)");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+
+    #[test]
+    fn silent_peer_is_suspected_until_seen() {
+        let clock = MockClock::new();
+        let mut liveness = Liveness::new(Duration::from_millis(10), Duration::from_millis(10));
+        liveness.track(&["n2".to_string()], clock.now());
+
+        assert!(!liveness.is_suspect("n2", clock.now()));
+
+        clock.advance(Duration::from_millis(20));
+        assert!(liveness.is_suspect("n2", clock.now()));
+        assert!(!liveness.is_suspect("c1", clock.now()));
+
+        liveness.seen("n2", clock.now());
+        assert!(!liveness.is_suspect("n2", clock.now()));
+    }
+}
@@ -1,62 +1,244 @@
-mod storage;
-
-use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
-use std::collections::{BTreeMap, HashMap};
+mod clock;
+#[cfg(test)]
+mod fault;
+mod liveness;
+mod network;
+mod reorder;
+mod reply_cache;
+mod workload;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
-use std::fmt::{Debug, Display};
-#[cfg(feature = "log_to_file")]
-use std::fs::File;
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::thread::sleep;
-use std::time::Duration;
-
-use crate::storage::{ClientPacket, Storage, StoragePacket};
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinSet;
+
+use crate::clock::{Clock, TokioClock};
+#[cfg(test)]
+use crate::fault::FaultInjector;
+use crate::liveness::Liveness;
+use crate::network::{FlushPolicy, Network};
+use crate::reply_cache::ReplyCache;
+use crate::workload::{
+    Broadcast, Echo, GCounter, GeneratePayload, Kafka, KeyValue, Rng, SeqKvCounter, Timer, Txn,
+    UniqueIds, Workload,
+};
+use maelstorm_distrib_challanges::storage::{
+    self, snapshot_config, storage_addr, AsyncStorageClient, Storage, StorageClient, WireFormat,
+};
+use maelstorm_distrib_challanges::{
+    id_kind, Body, Control, Envelope, EnvelopeBody, IdKind, LogEntry, MaelstromError, Message,
+    NodePayload, Payload, Provenance, ReadResult,
+};
 use thiserror::Error;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
 
+/// The debug log path from `MAELSTROM_LOG`, falling back to a per-process file in the
+/// temp dir so nodes sharing a host don't interleave their logs.
 #[cfg(feature = "log_to_file")]
-const DEBUG_FILE_PATH: &str = "/home/cryme/RustroverProjects/maelstorm_distrib_challanges/res.txt";
+fn debug_log_path() -> std::path::PathBuf {
+    std::env::var_os("MAELSTROM_LOG")
+        .map(Into::into)
+        .unwrap_or_else(|| {
+            std::env::temp_dir().join(format!("maelstrom-{}.log", std::process::id()))
+        })
+}
 
-fn is_storage_spawned() -> bool {
-    let Ok(mut stream) = TcpStream::connect("127.0.0.1:14081") else {
-        return false;
-    };
+/// The per-key kafka retention limit from `MAELSTROM_KAFKA_RETENTION`, unlimited if unset.
+fn kafka_retention() -> Option<usize> {
+    std::env::var("MAELSTROM_KAFKA_RETENTION")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+}
 
-    let Ok(_) = stream.write(&bincode::serialize(&ClientPacket::Hello).unwrap()) else {
-        return false;
-    };
+/// How many entries behind its owner a kafka replica's copy may be and still serve polls,
+/// from `MAELSTROM_KAFKA_MAX_STALENESS`. Unbounded if unset.
+fn kafka_max_staleness() -> Option<usize> {
+    std::env::var("MAELSTROM_KAFKA_MAX_STALENESS")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+}
 
-    let mut buff = [0u8; 1024];
+/// When output is flushed, from `MAELSTROM_FLUSH`: `every:<n>` messages or `interval:<ms>`.
+/// After every message if unset or unreadable.
+fn output_flush_policy() -> FlushPolicy {
+    let policy = std::env::var("MAELSTROM_FLUSH").unwrap_or_default();
+
+    match policy.split_once(':') {
+        Some(("every", n)) => n
+            .parse()
+            .map_or(FlushPolicy::Immediate, FlushPolicy::EveryN),
+        Some(("interval", ms)) => ms.parse().map_or(FlushPolicy::Immediate, |ms| {
+            FlushPolicy::Interval(Duration::from_millis(ms))
+        }),
+        _ => FlushPolicy::Immediate,
+    }
+}
 
-    let Ok(count) = stream.read(&mut buff) else {
-        return false;
-    };
+/// Logs to stderr, which Maelstrom keeps per node, filtered by `RUST_LOG`. With the
+/// `log_to_file` feature everything is also written to [`debug_log_path`].
+fn init_tracing() {
+    let stderr = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .with_filter(EnvFilter::from_default_env());
 
-    let Ok(packet) = bincode::deserialize::<StoragePacket>(&buff[..count]) else {
-        return false;
-    };
+    #[cfg(feature = "log_to_file")]
+    let file = std::fs::File::create(debug_log_path()).ok().map(|file| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(Mutex::new(file))
+            .with_ansi(false)
+    });
+    #[cfg(not(feature = "log_to_file"))]
+    let file: Option<tracing_subscriber::layer::Identity> = None;
+
+    let _ = tracing_subscriber::registry()
+        .with(stderr)
+        .with(file)
+        .try_init();
+}
 
-    let StoragePacket::Hello = packet else {
-        return false;
-    };
+const SEQ_KV: &str = "seq-kv";
+
+/// How long a [`SeqKv`] request waits for the service before it is given up.
+const SEQ_KV_TIMEOUT: Duration = Duration::from_millis(500);
 
-    true
+/// How many nodes keep a copy of each kafka log, the owner included.
+const REPLICATION_FACTOR: usize = 3;
+
+/// Attempts at a forwarded request, and how long each one waits, before the client is told
+/// it timed out.
+const FORWARD_ATTEMPTS: usize = 3;
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long the main loop waits for input when the workload has no timer due.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Lines read ahead of the handler. Once that many wait, stdin isn't read until one is taken.
+const INBOUND_CAPACITY: usize = 1024;
+
+/// How often every node pings its peers.
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a peer may stay silent before it is suspected and skipped as a gossip target.
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many replies are kept for answering retransmitted requests.
+const REPLY_CACHE_CAPACITY: usize = 4096;
+
+fn is_storage_spawned(addr: SocketAddr) -> bool {
+    StorageClient::connect(addr).is_ok()
+}
+
+/// Whether the workload named on the command line keeps its logs in the storage service.
+/// Only then is the service started or connected to.
+fn needs_storage(name: Option<&str>) -> bool {
+    matches!(name, None | Some("kafka") | Some("kafka-strict"))
 }
 
+/// Picks the workload named on the command line.
+fn select_workload(name: Option<&str>, storage_addr: SocketAddr) -> Box<dyn Workload> {
+    match name {
+        Some("echo") => Box::new(Echo),
+        Some("broadcast") => Box::<Broadcast>::default(),
+        Some("broadcast-hub") => Box::new(Broadcast::with_hub()),
+        Some("counter") => Box::<GCounter>::default(),
+        Some("counter-quorum") => Box::new(GCounter::with_quorum_reads()),
+        Some("counter-seq-kv") => Box::<SeqKvCounter>::default(),
+        Some("kv") | Some("lin-kv") => Box::<KeyValue>::default(),
+        Some("txn") => Box::<Txn>::default(),
+        // Kafka is also what the node ran before workloads were selectable.
+        name @ (None | Some("kafka") | Some("kafka-strict")) => {
+            let mut kafka = Kafka::new(AsyncStorageClient::new(storage_addr));
+            if name == Some("kafka-strict") {
+                kafka = kafka.with_strict_commits();
+            }
+            if let Some(limit) = kafka_max_staleness() {
+                kafka = kafka.with_max_staleness(limit);
+            }
+
+            match kafka_retention() {
+                Some(limit) => Box::new(kafka.with_retention(limit)),
+                None => Box::new(kafka),
+            }
+        }
+        Some(other) => {
+            eprintln!("unknown workload: {other}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs the workload named by the first argument over stdin. `replay <file> [workload]`
+/// instead feeds the newline-delimited messages of a recorded run from `file`, to reproduce
+/// it offline. The file should only hold the messages one node received.
 fn main() {
-    if !is_storage_spawned() {
-        Storage::run();
-        sleep(Duration::from_secs(1));
+    init_tracing();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let (input, workload): (Box<dyn Read + Send>, _) = match args.first().map(String::as_str) {
+        Some("replay") => {
+            let Some(path) = args.get(1) else {
+                eprintln!("usage: replay <file> [workload]");
+                std::process::exit(1);
+            };
+
+            let file = std::fs::File::open(path).unwrap_or_else(|err| {
+                eprintln!("can't open {path}: {err}");
+                std::process::exit(1);
+            });
+
+            (Box::new(file), args.get(2))
+        }
+        _ => (Box::new(std::io::stdin()), args.first()),
+    };
+
+    let workload = workload.map(String::as_str);
+
+    // Generate brings its own payload enum, so it runs on a node of its own type.
+    if workload == Some("generate") {
+        let generate: Box<dyn Workload<GeneratePayload>> = Box::<UniqueIds>::default();
+
+        NodeBuilder::with_workload(input, std::io::stdout(), generate)
+            .flush_policy(output_flush_policy())
+            .build()
+            .run();
+        return;
     }
 
-    let std_in = std::io::stdin().lock();
-    let std_out = std::io::stdout().lock();
-    let storage_connection = TcpStream::connect("127.0.0.1:14081").unwrap();
+    let mut storage_addr = storage_addr();
+    let mut storage = None;
+
+    if needs_storage(workload) && !is_storage_spawned(storage_addr) {
+        let handle =
+            Storage::run(storage_addr, snapshot_config(), WireFormat::default(), true).unwrap();
+        storage_addr = handle.addr();
+        storage = Some(handle);
+    }
 
-    let node = Node::new(std_in, std_out, storage_connection);
+    let workload = select_workload(workload, storage_addr);
+    let node = NodeBuilder::new(input, std::io::stdout())
+        .workload(workload)
+        .flush_policy(output_flush_policy())
+        .build();
 
     node.run();
+
+    if let Some(storage) = storage {
+        storage.shutdown().unwrap();
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -65,10 +247,18 @@ enum NodeState {
     Initialized { id: String },
 }
 
-#[allow(dead_code)]
+impl std::fmt::Display for NodeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeState::Created => write!(f, "not initialized"),
+            NodeState::Initialized { id } => write!(f, "initialized as {id}"),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 enum NodeError {
-    #[error("Unacceptable payload for state: {0:#?}")]
+    #[error("Unacceptable payload, node is {0}")]
     UnacceptablePayloadForState(NodeState),
     #[error("Support unimplemented type")]
     CurrentlyUnsupported,
@@ -76,233 +266,649 @@ enum NodeError {
     IllegalPayloadType,
     #[error("Bad payload")]
     IllegalPayload,
-    #[error("Node id mismatch")]
-    NodeIdMismatch,
+    #[error("Addressed to {0}, not this node")]
+    NodeIdMismatch(String),
+    #[error("Node not initialized yet")]
+    NotInitialized,
+    /// A request came without a `msg_id`, so its reply couldn't be matched to it.
+    #[error("Request without msg_id")]
+    MissingMsgId,
+    #[error("Unknown source: {0}")]
+    UnknownSource(String),
+    /// A write to storage failed midway, it may or may not have been applied.
     #[error("Storage connection error")]
     StorageConnectionError,
+    /// A read from storage failed even after reconnecting. Nothing changed, so the client
+    /// may simply retry.
+    #[error("Storage unavailable")]
+    StorageUnavailable,
+    #[error("Key does not exist")]
+    KeyDoesNotExist,
+    #[error("Key already exists")]
+    KeyAlreadyExists,
+    #[error("Precondition failed")]
+    PreconditionFailed,
+    #[error("Unsupported payload type: {0}")]
+    UnsupportedType(String),
+    #[error("Transaction conflicts with a concurrent write")]
+    TxnConflict,
+    #[error("Not enough nodes answered to make up a quorum")]
+    QuorumUnavailable,
+    #[error("Rpc timed out")]
+    Timeout,
+    #[error("Remote error: {0:?}")]
+    Remote(MaelstromError),
+}
+
+/// `node_ids` as given in `init`, with duplicates dropped and `node_id` added if missing, so
+/// peers can be told apart from this node by id alone.
+fn normalize_node_ids(node_id: &str, node_ids: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut normalized: Vec<String> = node_ids
+        .into_iter()
+        .filter(|id| seen.insert(id.clone()))
+        .collect();
+
+    if !seen.contains(node_id) {
+        tracing::warn!(node_id, "node_ids is missing this node, adding it");
+        normalized.push(node_id.to_string());
+    }
+
+    normalized
 }
 
-struct Node<Input, Output, StorageConnection> {
-    connection: StorageConnection,
+/// A reply still being worked out, see [`NodeCtx::defer`].
+type Deferred<P = Payload> = Pin<Box<dyn Future<Output = Result<P, NodeError>> + Send>>;
+
+/// The request a reply answers, as far as the reply needs to know it.
+struct Request {
+    src: String,
+    /// This node, as the request addressed it.
+    dst: String,
+    msg_id: Option<u64>,
+    is_reply: bool,
+}
+
+impl Request {
+    fn of<P: NodePayload>(message: &Message<P>) -> Self {
+        Self {
+            src: message.src.clone(),
+            dst: message.dst.clone(),
+            msg_id: message.body.msg_id,
+            is_reply: message.body.payload.is_reply(),
+        }
+    }
+
+    /// The `msg_id` the reply is cached under, if it is cached at all. Only client requests
+    /// are, those are what Maelstrom retransmits. Replies are never answered, and requests
+    /// from peers would only crowd the clients' replies out of the cache.
+    fn cache_id(&self) -> Option<u64> {
+        self.msg_id
+            .filter(|_| !self.is_reply && id_kind(&self.src) == IdKind::Client)
+    }
+
+    /// A reply without a msg_id couldn't even be pointed at.
+    fn unanswerable(&self) -> bool {
+        self.msg_id.is_none() && self.is_reply
+    }
+}
+
+/// Runs a workload over payloads `P`, the shared [`Payload`] unless the workload brings its
+/// own enum.
+struct Node<Input, Output: Write, P = Payload> {
     state: NodeState,
-    next_message_id: i32,
-    #[cfg(feature = "log_to_file")]
-    log_file: File,
     all_node_ids: Vec<String>,
-    commit_offsets: HashMap<String, usize>,
+    /// Copies kept of each replicated log, capped by the number of nodes.
+    replication_factor: usize,
+    workload: Box<dyn Workload<P>>,
     input: Option<Input>,
+    network: Network<P>,
+    /// Drives every timer of the node and its workload.
+    clock: Arc<dyn Clock>,
+    liveness: Liveness,
+    /// Draws the jitter of every timer of the node and its workload.
+    rng: Rng,
+    /// Received messages per payload type, dumped to the log at EOF.
+    received: HashMap<&'static str, u64>,
+    replies: ReplyCache<P>,
+    /// Set by [`NodeCtx::defer`] while a message is handled.
+    deferred: Option<Deferred<P>>,
+    /// Deferred replies not handed to the event loop yet.
+    pending_replies: Vec<(Request, Deferred<P>)>,
+    /// Client requests, by source and cache id, whose deferred reply hasn't resolved yet.
+    /// Retransmissions of those are dropped, the reply to the original answers them too.
+    answering: HashSet<(String, u64)>,
+    output: Arc<Mutex<BufWriter<Output>>>,
+}
+
+/// The part of a [`Node`] a [`Workload`] gets access to while handling a message or a tick.
+struct NodeCtx<'a, P = Payload> {
+    node_id: &'a str,
+    all_node_ids: &'a [String],
+    replication_factor: usize,
+    network: &'a Network<P>,
+    clock: &'a dyn Clock,
+    liveness: &'a Liveness,
+    rng: &'a mut Rng,
+    deferred: &'a mut Option<Deferred<P>>,
+}
+
+/// Configures a [`Node`] before it starts. Anything left unset keeps its default: the echo
+/// workload and [`FlushPolicy::Immediate`].
+struct NodeBuilder<Input, Output, P = Payload> {
+    input: Input,
     output: Output,
+    workload: Box<dyn Workload<P>>,
+    flush_policy: FlushPolicy,
+    forward_attempts: usize,
+    forward_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    #[cfg(test)]
+    faults: Option<FaultInjector<P>>,
+}
+
+impl<Input: Read + Send + 'static, Output: Write + Send + 'static> NodeBuilder<Input, Output> {
+    fn new(input: Input, output: Output) -> Self {
+        Self::with_workload(input, output, Box::new(Echo))
+    }
 }
 
-impl<Input: Read, Output: Write, StorageConnection: Read + Write>
-    Node<Input, Output, StorageConnection>
+impl<Input: Read + Send + 'static, Output: Write + Send + 'static, P: NodePayload>
+    NodeBuilder<Input, Output, P>
 {
-    fn send(&mut self, packet: ClientPacket) -> anyhow::Result<StoragePacket> {
-        let mut res = [0u8; 1024];
+    /// A builder for a node of `workload`, which has no default for payloads other than
+    /// the shared ones.
+    fn with_workload(input: Input, output: Output, workload: Box<dyn Workload<P>>) -> Self {
+        Self {
+            input,
+            output,
+            workload,
+            flush_policy: FlushPolicy::Immediate,
+            forward_attempts: FORWARD_ATTEMPTS,
+            forward_timeout: FORWARD_TIMEOUT,
+            clock: Arc::new(TokioClock),
+            #[cfg(test)]
+            faults: None,
+        }
+    }
+
+    fn workload(mut self, workload: Box<dyn Workload<P>>) -> Self {
+        self.workload = workload;
+        self
+    }
 
-        let d = bincode::serialize(&packet)?;
+    fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
 
-        let _ = self.connection.write(&d)?;
+    /// How often a forwarded request is tried, and how long each attempt waits for a reply.
+    #[cfg(test)]
+    fn forward_retries(mut self, attempts: usize, timeout: Duration) -> Self {
+        self.forward_attempts = attempts;
+        self.forward_timeout = timeout;
+        self
+    }
 
-        let count = self.connection.read(&mut res)?;
+    /// Replaces the wall clock, for tests that step time by hand.
+    #[cfg(test)]
+    fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
 
-        Ok(bincode::deserialize(&res[..count])?)
+    /// Sends everything through `faults`, for tests of how the node copes with a lossy
+    /// network.
+    #[cfg(test)]
+    fn faults(mut self, faults: FaultInjector<P>) -> Self {
+        self.faults = Some(faults);
+        self
     }
 
-    fn new(
-        input: Input,
-        output: Output,
-        storage_connection: StorageConnection,
-    ) -> Node<Input, Output, StorageConnection> {
-        Self {
-            connection: storage_connection,
+    fn build(self) -> Node<Input, Output, P> {
+        let output = Arc::new(Mutex::new(BufWriter::new(self.output)));
+
+        let mut network = Network::new(output.clone());
+        network.set_flush_policy(self.flush_policy);
+        network.set_forward_retries(self.forward_attempts, self.forward_timeout);
+        #[cfg(test)]
+        if let Some(faults) = self.faults {
+            network.set_faults(faults);
+        }
+
+        Node {
             state: NodeState::Created,
-            next_message_id: i32::MIN,
-            #[cfg(feature = "log_to_file")]
-            log_file: File::create(DEBUG_FILE_PATH).unwrap(),
             all_node_ids: Vec::new(),
-            commit_offsets: HashMap::new(),
-            input: Some(input),
+            replication_factor: REPLICATION_FACTOR,
+            workload: self.workload,
+            input: Some(self.input),
+            network,
+            clock: self.clock,
+            liveness: Liveness::new(PING_INTERVAL, SUSPECT_TIMEOUT),
+            rng: Rng::default(),
+            received: HashMap::new(),
+            replies: ReplyCache::new(REPLY_CACHE_CAPACITY),
+            deferred: None,
+            pending_replies: Vec::new(),
+            answering: HashSet::new(),
             output,
         }
     }
+}
 
-    #[allow(unused_variables)]
-    fn log_to_file(&mut self, data: &dyn Display) {
-        #[cfg(feature = "log_to_file")]
-        writeln!(self.log_file, "{data}").unwrap();
+impl<Input: Read + Send + 'static, Output: Write + Send + 'static, P: NodePayload>
+    Node<Input, Output, P>
+{
+    #[cfg(test)]
+    fn new(input: Input, output: Output, workload: Box<dyn Workload<P>>) -> Self {
+        NodeBuilder::with_workload(input, output, workload).build()
     }
 
-    fn next_message_id(&mut self) -> i32 {
-        self.next_message_id += 1;
+    /// Splits the node into its workload and the context the workload operates on.
+    /// Before initialization the context's `node_id` is empty.
+    fn split(&mut self) -> (&mut dyn Workload<P>, NodeCtx<'_, P>) {
+        let node_id = match &self.state {
+            NodeState::Created => "",
+            NodeState::Initialized { id } => id.as_str(),
+        };
+
+        (
+            self.workload.as_mut(),
+            NodeCtx {
+                node_id,
+                all_node_ids: &self.all_node_ids,
+                replication_factor: self.replication_factor,
+                network: &self.network,
+                clock: self.clock.as_ref(),
+                liveness: &self.liveness,
+                rng: &mut self.rng,
+                deferred: &mut self.deferred,
+            },
+        )
+    }
 
-        self.next_message_id
+    fn next_message_id(&self) -> u64 {
+        self.network.next_message_id()
     }
 
     fn run(mut self) {
-        if self.input.is_none() {
+        let Some(input) = self.input.take() else {
             return;
+        };
+
+        let span = tracing::info_span!("node", workload = self.workload.name());
+        tracing::info!(parent: &span, "created");
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(self.event_loop(input).instrument(span));
+
+        // The stdin reader may still be blocked on a read, don't wait for it.
+        rt.shutdown_background();
+    }
+
+    /// Reads stdin on a blocking task and dispatches lines and timer ticks from one loop, so
+    /// the workload itself never has to be shared between tasks. Replies to pending RPCs are
+    /// routed straight from the reader to the tasks waiting for them. Deferred replies run
+    /// as tasks of the loop and are sent from it once they resolve; at EOF the loop waits
+    /// for those still running before it shuts down.
+    async fn event_loop(mut self, input: Input) {
+        let (sender, mut inbound) = mpsc::channel(INBOUND_CAPACITY);
+        let network = self.network.clone();
+
+        tokio::task::spawn_blocking(move || {
+            for line in BufReader::new(input).lines() {
+                let Ok(line) = line else { break };
+
+                let Some(line) = network.route_reply(line) else {
+                    continue;
+                };
+
+                let line = match sender.try_send(line) {
+                    Ok(()) => continue,
+                    Err(TrySendError::Closed(_)) => break,
+                    Err(TrySendError::Full(line)) => line,
+                };
+
+                tracing::warn!(
+                    capacity = INBOUND_CAPACITY,
+                    "inbound queue full, handler is falling behind; pausing stdin"
+                );
+
+                // Replies queued behind this line wait too, pending RPCs then run into their
+                // timeouts rather than deadlocking the node.
+                if sender.blocking_send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut flush_timer = match self.network.flush_policy() {
+            FlushPolicy::Interval(interval) => Some(Timer::new(interval)),
+            FlushPolicy::Immediate | FlushPolicy::EveryN(_) => None,
+        };
+
+        let mut replying = JoinSet::new();
+        let mut reading = true;
+
+        loop {
+            let now = self.clock.now();
+            let timeout = self
+                .workload
+                .next_tick(now)
+                .into_iter()
+                .chain(Some(self.liveness.next_ping(now)))
+                .chain(flush_timer.as_ref().map(|timer| timer.remaining(now)))
+                .min()
+                .unwrap_or(IDLE_TIMEOUT);
+
+            let handled = tokio::select! {
+                line = inbound.recv(), if reading => match line {
+                    Some(line) => self.handle_line(&line),
+                    None => {
+                        reading = false;
+                        Ok(())
+                    }
+                },
+                Some(replied) = replying.join_next() => match replied {
+                    Ok((request, reply)) => self.send_reply(request, reply),
+                    Err(err) => {
+                        tracing::warn!(%err, "deferred reply failed");
+                        Ok(())
+                    }
+                },
+                _ = self.clock.sleep(timeout) => Ok(()),
+            };
+
+            for (request, reply) in self.pending_replies.drain(..) {
+                replying.spawn(async move { (request, reply.await) });
+            }
+
+            if !reading && replying.is_empty() {
+                break;
+            }
+
+            self.tick();
+
+            let now = self.clock.now();
+            let flushed = if flush_timer
+                .as_mut()
+                .is_some_and(|timer| timer.fire(now, &mut self.rng))
+            {
+                self.flush_output()
+            } else {
+                Ok(())
+            };
+
+            // Maelstrom closing our stdout means the test is over.
+            if let Err(err) = handled.and(flushed) {
+                tracing::info!(%err, "output closed");
+                break;
+            }
         }
 
-        self.log_to_file(&"Created!");
+        self.on_shutdown();
+    }
 
-        let input = self.input.take().unwrap();
+    /// Runs once the test is over, whether stdin hit EOF or stdout was closed. This is the
+    /// single place for end-of-test bookkeeping: outstanding work is logged here and the output
+    /// gets its final flush. The log file needs no closing, the tracing layer writes through.
+    fn on_shutdown(&mut self) {
+        let pending = self.network.pending_rpcs();
+        if !pending.is_empty() {
+            tracing::info!(?pending, "rpcs still waiting for a reply");
+        }
 
-        let msg = serde_json::Deserializer::from_reader(input).into_iter::<Message>();
+        if let NodeState::Initialized { .. } = self.state {
+            let (workload, mut ctx) = self.split();
+            workload.on_shutdown(&mut ctx);
+        }
 
-        for m in msg {
-            self.log_to_file(&format!("\n--> {m:#?}"));
+        self.log_counters();
 
-            let Ok(message) = m else { continue };
+        let _ = self.flush_output();
+    }
+
+    /// Runs the workload's timers and pings the peers when due. The event loop calls this
+    /// after every message and timeout, tests call it after advancing a mock clock.
+    fn tick(&mut self) {
+        let NodeState::Initialized { .. } = self.state else {
+            return;
+        };
 
-            self.handle_message(message);
+        self.observe_repliers();
+
+        let (workload, mut ctx) = self.split();
+        workload.tick(&mut ctx);
+
+        if self.liveness.ping_due(self.clock.now(), &mut self.rng) {
+            self.ping_peers();
         }
     }
 
-    fn handle_message(&mut self, message: Message) {
-        if let Some(reply) = self.build_reply(message) {
-            self.send_to_network(&reply);
+    /// Marks the peers whose replies were routed to waiting RPCs as seen. Those replies never
+    /// pass [`Node::proceed_message`], a peer only answering RPCs would be suspected otherwise.
+    fn observe_repliers(&mut self) {
+        let now = self.clock.now();
+
+        for peer in self.network.take_repliers() {
+            self.liveness.seen(&peer, now);
         }
     }
 
-    fn send_to_network<T: Sized + Serialize>(&mut self, data: &T) {
-        let mut data = serde_json::to_string(data).unwrap();
+    /// Pings every peer; their pongs, like any other message from them, keep them alive.
+    fn ping_peers(&self) {
+        self.liveness.log(self.clock.now());
+
+        for peer in self.liveness.peers() {
+            if self.network.send(peer, P::ping()).is_err() {
+                return;
+            }
+        }
+    }
 
-        data.push('\n');
+    fn log_counters(&self) {
+        let mut received: BTreeMap<_, _> = self.received.clone().into_iter().collect();
+        for (r#type, count) in self.network.routed() {
+            *received.entry(r#type).or_default() += count;
+        }
+        let sent: BTreeMap<_, _> = self.network.sent().into_iter().collect();
 
-        self.log_to_file(&format!("\n<-- {data}"));
-        self.output.write_all(data.as_bytes()).unwrap();
-        self.log_to_file(&"\n--");
+        for (r#type, count) in received {
+            tracing::info!(r#type, count, "received");
+        }
+        for (r#type, count) in sent {
+            tracing::info!(r#type, count, "sent");
+        }
     }
 
-    fn wrap_err(&self, err: NodeError) -> Payload {
-        Payload::Error {
-            code: match &err {
-                NodeError::UnacceptablePayloadForState(..)
-                | NodeError::IllegalPayloadType
-                | NodeError::IllegalPayload
-                | NodeError::NodeIdMismatch => MaelstromError::MalformedRequest,
+    fn handle_line(&mut self, line: &str) -> io::Result<()> {
+        if line.trim().is_empty() {
+            return Ok(());
+        }
 
-                NodeError::CurrentlyUnsupported => MaelstromError::NotSupported,
-                NodeError::StorageConnectionError => MaelstromError::Crash,
-            },
-            text: format!("{err:#?}"),
+        let message = match serde_json::from_str::<Message<P>>(line) {
+            Ok(message) => message,
+            Err(err) => {
+                let Ok(envelope) = serde_json::from_str::<Envelope>(line) else {
+                    tracing::warn!(line, %err, "unparseable message");
+                    return Ok(());
+                };
+
+                tracing::warn!(line, %err, "malformed message");
+
+                let reply = self.wrap_payload(
+                    P::error(MaelstromError::MalformedRequest, err.to_string()),
+                    envelope.dst,
+                    envelope.src,
+                    envelope.body.msg_id,
+                );
+                return self.send_to_network(reply);
+            }
+        };
+
+        self.handle_message(message)
+    }
+
+    fn handle_message(&mut self, message: Message<P>) -> io::Result<()> {
+        let span = tracing::debug_span!(
+            "message",
+            r#type = message.body.payload.type_name(),
+            src = %message.src,
+            msg_id = message.body.msg_id,
+        );
+        let _entered = span.enter();
+
+        tracing::debug!(?message, "received");
+
+        match self.build_reply(message) {
+            Some(reply) => self.send_to_network(reply),
+            None => Ok(()),
         }
     }
 
-    fn proceed_message(&mut self, message: Message) -> Result<Payload, NodeError> {
-        match &self.state {
-            NodeState::Created => match message.body.payload {
-                Payload::Init { node_id, node_ids } => {
-                    if self.state != NodeState::Created {
-                        return Err(NodeError::UnacceptablePayloadForState(self.state.clone()));
-                    }
+    fn send_to_network(&self, message: Message<P>) -> io::Result<()> {
+        self.network.send_to_network(message)
+    }
 
-                    self.all_node_ids = node_ids;
-                    self.state = NodeState::Initialized { id: node_id };
+    fn flush_output(&self) -> io::Result<()> {
+        self.output.lock().unwrap().flush()
+    }
 
-                    Ok(Payload::InitOk)
-                }
+    fn wrap_err(&self, err: NodeError) -> P {
+        let code = match &err {
+            NodeError::UnacceptablePayloadForState(..)
+            | NodeError::IllegalPayloadType
+            | NodeError::IllegalPayload
+            | NodeError::NodeIdMismatch(..)
+            | NodeError::MissingMsgId => MaelstromError::MalformedRequest,
 
-                _ => Err(NodeError::UnacceptablePayloadForState(self.state.clone())),
-            },
+            NodeError::CurrentlyUnsupported | NodeError::UnsupportedType(..) => {
+                MaelstromError::NotSupported
+            }
+            NodeError::UnknownSource(..) => MaelstromError::NodeNotFound,
+            NodeError::NotInitialized
+            | NodeError::QuorumUnavailable
+            | NodeError::StorageUnavailable => MaelstromError::TemporarilyUnavailable,
+            NodeError::StorageConnectionError => MaelstromError::Crash,
+            NodeError::KeyDoesNotExist => MaelstromError::KeyDoesNotExist,
+            NodeError::KeyAlreadyExists => MaelstromError::KeyAlreadyExists,
+            NodeError::PreconditionFailed => MaelstromError::PreconditionFailed,
+            NodeError::TxnConflict => MaelstromError::TxnConflict,
+            NodeError::Timeout => MaelstromError::Timeout,
+            NodeError::Remote(code) => code.clone(),
+        };
 
-            NodeState::Initialized { id } => {
-                if id != &message.dst {
-                    return Err(NodeError::NodeIdMismatch);
-                }
+        P::error(code, err.to_string())
+    }
 
-                match message.body.payload {
-                    Payload::Send { key, msg } => {
-                        let offset = self.send(ClientPacket::Store {
-                            key: key.clone(),
-                            msg,
-                        });
+    fn proceed_message(&mut self, message: Message<P>) -> Result<P, NodeError> {
+        self.network.observe_clock(message.body.ts);
+        *self
+            .received
+            .entry(message.body.payload.type_name())
+            .or_default() += 1;
 
-                        let Ok(offset) = offset else {
-                            return Err(NodeError::StorageConnectionError);
-                        };
+        self.validate(&message)?;
 
-                        let StoragePacket::Store(offset) = offset else {
-                            return Err(NodeError::StorageConnectionError);
-                        };
+        let NodeState::Initialized { .. } = self.state else {
+            let Ok((node_id, node_ids)) = message.body.payload.into_init() else {
+                return Err(NodeError::NotInitialized);
+            };
 
-                        Ok(Payload::SendOk { offset })
-                    }
+            let node_ids = normalize_node_ids(&node_id, node_ids);
 
-                    Payload::Poll { offsets } => {
-                        let mut messages = BTreeMap::new();
-                        for (key, offset) in &offsets {
-                            let v = self.send(ClientPacket::Get {
-                                key: key.clone(),
-                                offset: *offset,
-                            });
+            self.network.set_node_id(&node_id);
+            self.rng = Rng::seeded(&node_id);
+            self.liveness.track(
+                node_ids.iter().filter(|peer| **peer != node_id),
+                self.clock.now(),
+            );
+            self.all_node_ids = node_ids;
+            self.state = NodeState::Initialized { id: node_id };
 
-                            let Ok(v) = v else {
-                                continue;
-                            };
+            return Ok(P::init_ok());
+        };
 
-                            let StoragePacket::Get(v) = v else {
-                                continue;
-                            };
+        self.liveness.seen(&message.src, self.clock.now());
+        self.observe_repliers();
 
-                            let vals: Vec<[usize; 2]> = v
-                                .iter()
-                                .enumerate()
-                                .map(|(i, val)| [offset + i, *val])
-                                .collect();
+        match message.body.payload.control() {
+            // Maelstrom may retry an init, answer it again as long as it agrees.
+            Some(Control::Init { .. }) => Ok(P::init_ok()),
 
-                            messages.insert(key.clone(), vals);
-                        }
+            Some(Control::Ping) => Ok(P::pong()),
+            Some(Control::Pong) => Ok(P::dont_reply()),
 
-                        Ok(Payload::PollOk { messages })
-                    }
+            _ => {
+                let (workload, mut ctx) = self.split();
 
-                    Payload::CommitOffsets { offsets } => {
-                        for (key, offset) in offsets {
-                            self.commit_offsets.insert(key, offset);
-                        }
+                workload.handle(message, &mut ctx)
+            }
+        }
+    }
 
-                        Ok(Payload::CommitOffsetsOk)
-                    }
+    /// Checks whether the node would accept `message` in its current state, without touching
+    /// that state. [`Node::proceed_message`] runs the same checks before handling anything, so
+    /// a message passing here only fails in the workload.
+    fn validate(&self, message: &Message<P>) -> Result<(), NodeError> {
+        if message.body.msg_id.is_none() && !message.body.payload.is_reply() {
+            return Err(NodeError::MissingMsgId);
+        }
 
-                    Payload::ListCommittedOffsets { keys } => {
-                        let mut offsets = BTreeMap::new();
+        match &self.state {
+            NodeState::Created => match message.body.payload.control() {
+                Some(Control::Init { .. }) => Ok(()),
 
-                        for key in keys {
-                            if let Some(val) = self.commit_offsets.get(&key) {
-                                offsets.insert(key, *val);
-                            }
-                        }
+                Some(Control::InitOk | Control::DontReply) => Err(NodeError::IllegalPayloadType),
 
-                        Ok(Payload::ListCommittedOffsetsOk { offsets })
-                    }
+                // Could well succeed once init arrives, so not the client's fault.
+                _ => Err(NodeError::NotInitialized),
+            },
 
-                    Payload::Error { .. }
-                    | Payload::CommitOffsetsOk
-                    | Payload::ListCommittedOffsetsOk { .. }
-                    | Payload::SendOk { .. }
-                    | Payload::PollOk { .. } => Ok(Payload::DontReply),
+            NodeState::Initialized { id } => {
+                if id != &message.dst {
+                    return Err(NodeError::NodeIdMismatch(message.dst.clone()));
+                }
+
+                if !self.is_known_source(&message.src) {
+                    return Err(NodeError::UnknownSource(message.src.clone()));
+                }
 
-                    Payload::InitOk | Payload::DontReply => Err(NodeError::IllegalPayloadType),
+                match message.body.payload.control() {
+                    Some(Control::InitOk | Control::DontReply) => {
+                        Err(NodeError::IllegalPayloadType)
+                    }
 
-                    Payload::Init { .. } => {
+                    Some(Control::Init { node_id }) if node_id != id => {
                         Err(NodeError::UnacceptablePayloadForState(self.state.clone()))
                     }
+
+                    Some(Control::Unknown(r#type)) => {
+                        Err(NodeError::UnsupportedType(r#type.to_string()))
+                    }
+
+                    _ => Ok(()),
                 }
             }
         }
     }
 
+    /// Whether `src` is a client, a node of the cluster or a service this node talks to.
+    fn is_known_source(&self, src: &str) -> bool {
+        id_kind(src) == IdKind::Client
+            || src == SEQ_KV
+            || self.all_node_ids.iter().any(|id| id == src)
+    }
+
     fn on_err(&mut self, _error: &dyn Error) {}
 
     fn wrap_payload(
         &mut self,
-        payload: Payload,
+        payload: P,
         src: String,
         dst: String,
-        msg_id: Option<i32>,
-    ) -> Message {
+        msg_id: Option<u64>,
+    ) -> Message<P> {
         Message {
             src: if let NodeState::Initialized { id } = &self.state {
                 id.clone()
@@ -313,24 +919,77 @@ impl<Input: Read, Output: Write, StorageConnection: Read + Write>
             body: Body {
                 msg_id: Some(self.next_message_id()),
                 in_reply_to: msg_id,
+                ts: None,
                 payload,
             },
         }
     }
 
-    fn build_reply(&mut self, message: Message) -> Option<Message> {
-        let dst = message.dst.clone();
-        let src = message.src.clone();
-        let msg_id = message.body.msg_id;
+    fn build_reply(&mut self, message: Message<P>) -> Option<Message<P>> {
+        let request = Request::of(&message);
+
+        if let Some(payload) = request
+            .cache_id()
+            .and_then(|msg_id| self.replies.get(&request.src, msg_id))
+        {
+            tracing::debug!("answering retransmitted request from the reply cache");
+
+            return Some(self.wrap_payload(payload, request.dst, request.src, request.msg_id));
+        }
+
+        if let Some(msg_id) = request.cache_id() {
+            if self.answering.contains(&(request.src.clone(), msg_id)) {
+                tracing::debug!("dropping retransmitted request still being answered");
+
+                return None;
+            }
+        }
+
+        let reply = self.proceed_message(message);
+
+        if let (Ok(payload), Some(deferred)) = (&reply, self.deferred.take()) {
+            if payload.is_dont_reply() {
+                if let Some(msg_id) = request.cache_id() {
+                    self.answering.insert((request.src.clone(), msg_id));
+                }
+                self.pending_replies.push((request, deferred));
+
+                return None;
+            }
+        }
+
+        self.reply(request, reply)
+    }
+
+    /// Sends the reply to a deferred request once it resolved.
+    fn send_reply(&mut self, request: Request, reply: Result<P, NodeError>) -> io::Result<()> {
+        if let Some(msg_id) = request.cache_id() {
+            self.answering.remove(&(request.src.clone(), msg_id));
+        }
+
+        match self.reply(request, reply) {
+            Some(reply) => self.send_to_network(reply),
+            None => Ok(()),
+        }
+    }
 
-        let payload = match self.proceed_message(message) {
+    /// Turns what handling `request` came to into the message answering it, if any.
+    fn reply(&mut self, request: Request, reply: Result<P, NodeError>) -> Option<Message<P>> {
+        let payload = match reply {
+            Ok(payload) if payload.is_dont_reply() => return None,
             Ok(payload) => {
-                if let Payload::DontReply = payload {
-                    return None;
+                // Only successes, a request that failed changed nothing and may be retried.
+                if let Some(msg_id) = request.cache_id() {
+                    self.replies.insert(&request.src, msg_id, payload.clone());
                 }
 
                 payload
             }
+            Err(e) if request.unanswerable() => {
+                tracing::debug!(%e, "dropping reply without msg_id");
+
+                return None;
+            }
             Err(e) => {
                 self.on_err(&e);
 
@@ -338,118 +997,1691 @@ impl<Input: Read, Output: Write, StorageConnection: Read + Write>
             }
         };
 
-        Some(self.wrap_payload(payload, dst, src, msg_id))
+        if request.unanswerable() {
+            return None;
+        }
+
+        Some(self.wrap_payload(payload, request.dst, request.src, request.msg_id))
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct Message {
-    src: String,
-    #[serde(rename = "dest")]
-    dst: String,
-    body: Body,
+/// Flushes whatever the flush policy held back, so a node unwinding from a panic, or
+/// dropped without running to the end, still gets its last replies out. The log file needs
+/// no counterpart, the tracing layer writes it unbuffered.
+impl<Input, Output: Write, P> Drop for Node<Input, Output, P> {
+    fn drop(&mut self) {
+        let mut output = self.output.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Err(err) = output.flush() {
+            tracing::info!(%err, "flushing output on drop failed");
+        }
+    }
+}
+
+impl<'a, P: NodePayload> NodeCtx<'a, P> {
+    fn send_to_network(&self, message: Message<P>) -> io::Result<()> {
+        self.network.send_to_network(message)
+    }
+
+    /// Every node but this one.
+    fn peers(&self) -> Vec<&'a String> {
+        self.all_node_ids
+            .iter()
+            .filter(|node| **node != self.node_id)
+            .collect()
+    }
+
+    /// The node clock's time, what workload timers are polled with.
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Whether `peer` hasn't been heard from in a while. Gossip skips suspected peers until
+    /// they answer a ping again.
+    fn is_suspect(&self, peer: &str) -> bool {
+        self.liveness.is_suspect(peer, self.now())
+    }
+
+    /// Sends `payload` to `dst` as a new request from this node and returns its `msg_id`.
+    fn send(&self, dst: &str, payload: P) -> io::Result<u64> {
+        self.network.send(dst, payload)
+    }
+
+    /// Answers the request being handled once `reply` resolves instead of right away, the
+    /// node goes on handling other messages meanwhile. Returns what the workload's
+    /// [`Workload::handle`] should return.
+    fn defer(
+        &mut self,
+        reply: impl Future<Output = Result<P, NodeError>> + Send + 'static,
+    ) -> Result<P, NodeError> {
+        *self.deferred = Some(Box::pin(reply));
+
+        Ok(P::dont_reply())
+    }
+
+    /// Defers the reply to what forwarding `payload` to `dsts` comes back with, see
+    /// [`Network::forward_rpc`].
+    fn forward(&mut self, dsts: &[&str], payload: P) -> Result<P, NodeError> {
+        let network = self.network.clone();
+        let dsts: Vec<String> = dsts.iter().map(|dst| dst.to_string()).collect();
+
+        self.defer(async move { network.forward_rpc(&dsts, payload).await })
+    }
 }
 
-#[serde_with::skip_serializing_none]
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct Body {
-    msg_id: Option<i32>,
-    in_reply_to: Option<i32>,
-    #[serde(flatten)]
-    payload: Payload,
+impl NodeCtx<'_> {
+    /// A client of Maelstrom's `seq-kv` service, to be moved into a deferred reply.
+    fn seq_kv(&self) -> SeqKv {
+        SeqKv {
+            network: self.network.clone(),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Init {
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    InitOk,
-
-    Send {
-        key: String,
-        msg: usize,
-    },
-    SendOk {
-        offset: usize,
-    },
-
-    Poll {
-        offsets: BTreeMap<String, usize>,
-    },
-    PollOk {
-        #[serde(rename = "msgs")]
-        messages: BTreeMap<String, Vec<[usize; 2]>>,
-    },
-
-    CommitOffsets {
-        offsets: BTreeMap<String, usize>,
-    },
-    CommitOffsetsOk,
-
-    ListCommittedOffsets {
-        keys: Vec<String>,
-    },
-    ListCommittedOffsetsOk {
-        offsets: BTreeMap<String, usize>,
-    },
-
-    DontReply,
-
-    Error {
-        code: MaelstromError,
-        text: String,
-    },
+/// Requests to Maelstrom's sequentially consistent `seq-kv` service. Each is an rpc that
+/// gives up after [`SEQ_KV_TIMEOUT`], awaited in a deferred reply so the node goes on
+/// handling other messages meanwhile. Errors of the service come back as
+/// [`NodeError::Remote`], a missing key as `KeyDoesNotExist`.
+#[derive(Clone)]
+struct SeqKv {
+    network: Network,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Clone)]
-#[repr(u8)]
-enum MaelstromError {
-    /**
-        Indicates that the requested operation could not be completed within a Timeout.
-    */
-    Timeout = 0,
-    /**
-        Thrown when a client sends an RPC request to a node which does not exist.
-    */
-    NodeNotFound = 1,
-    /**
-        Use this error to indicate that a requested operation is not supported by the current implementation. Helpful for stubbing out APIs during development.
-    */
-    NotSupported = 10,
-    /**
-    Indicates that the operation definitely cannot be performed at this time--perhaps because the server is in a read-only state, has not yet been initialized, believes its peers to be down, and so on. Do not use this error for indeterminate cases, when the operation may actually have taken place.
-    */
-    TemporarilyUnavailable = 11,
-    /**
-        The client's request did not conform to the server's expectations, and could not possibly have been processed.
-    */
-    MalformedRequest = 12,
-    /**
-        Indicates that some kind of general, indefinite error occurred. Use this as a catch-all for errors you can't otherwise categorize, or as a starting point for your error handler: it's safe to return internal-error for every problem by default, then add special cases for more specific errors later.
-    */
-    Crash = 13,
-    /**
-        Indicates that some kind of general, definite error occurred. Use this as a catch-all for errors you can't otherwise categorize, when you specifically know that the requested operation has not taken place. For instance, you might encounter an indefinite failure during the prepare phase of a transaction: since you haven't started the commit process yet, the transaction can't have taken place. It's therefore safe to return a definite abort to the client.
-    */
-    Abort = 14,
-    /**
-        The client requested an operation on a key which does not exist (assuming the operation should not automatically create missing keys).
-    */
-    KeyDoesNotExist = 20,
-    /**
-        The client requested the creation of a key which already exists, and the server will not overwrite it.
-    */
-    KeyAlreadyExists = 21,
-    /**
-        The requested operation expected some conditions to hold, and those conditions were not met. For instance, a compare-and-set operation might assert that the value of a key is currently 5; if the value is 3, the server would return precondition-failed.
-    */
-    PreconditionFailed = 22,
-    /**
-        The requested transaction has been aborted because of a conflict with another transaction. Servers need not return this error on every conflict: they may choose to retry automatically instead.
-    */
-    TxnConflict = 30,
+impl SeqKv {
+    async fn read(
+        &self,
+        key: impl Into<serde_json::Value>,
+    ) -> Result<serde_json::Value, NodeError> {
+        match self
+            .network
+            .rpc_with_timeout(
+                SEQ_KV,
+                Payload::Read {
+                    key: Some(key.into()),
+                },
+                SEQ_KV_TIMEOUT,
+            )
+            .await?
+        {
+            Payload::ReadOk(ReadResult::Value { value }) => Ok(value),
+            _ => Err(NodeError::IllegalPayloadType),
+        }
+    }
+
+    async fn write(
+        &self,
+        key: impl Into<serde_json::Value>,
+        value: impl Into<serde_json::Value>,
+    ) -> Result<(), NodeError> {
+        let write = Payload::Write {
+            key: key.into(),
+            value: value.into(),
+        };
+
+        match self
+            .network
+            .rpc_with_timeout(SEQ_KV, write, SEQ_KV_TIMEOUT)
+            .await?
+        {
+            Payload::WriteOk => Ok(()),
+            _ => Err(NodeError::IllegalPayloadType),
+        }
+    }
+
+    /// Replaces the value of `key` if it is still `from`, failing with `PreconditionFailed`
+    /// otherwise. A missing key is created with `to` if `create_if_not_exists` is set.
+    async fn cas(
+        &self,
+        key: impl Into<serde_json::Value>,
+        from: impl Into<serde_json::Value>,
+        to: impl Into<serde_json::Value>,
+        create_if_not_exists: bool,
+    ) -> Result<(), NodeError> {
+        let cas = Payload::Cas {
+            key: key.into(),
+            from: from.into(),
+            to: to.into(),
+            create_if_not_exists: Some(create_if_not_exists),
+        };
+
+        match self
+            .network
+            .rpc_with_timeout(SEQ_KV, cas, SEQ_KV_TIMEOUT)
+            .await?
+        {
+            Payload::CasOk => Ok(()),
+            _ => Err(NodeError::IllegalPayloadType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::reorder::DEFAULT_REORDER_WINDOW;
+    use std::io::{empty, Cursor, Empty};
+
+    type TestNode = Node<Empty, Vec<u8>>;
+
+    fn test_node(workload: Box<dyn Workload>) -> TestNode {
+        Node::new(empty(), Vec::new(), workload)
+    }
+
+    fn message<P>(payload: P) -> Message<P> {
+        Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                msg_id: Some(1),
+                in_reply_to: None,
+                ts: None,
+                payload,
+            },
+        }
+    }
+
+    /// `msgs` as the input of a node, one JSON message per line.
+    pub(crate) fn script(msgs: &[impl AsRef<str>]) -> Cursor<Vec<u8>> {
+        let lines: Vec<&str> = msgs.iter().map(AsRef::as_ref).collect();
+
+        Cursor::new(lines.join("\n").into_bytes())
+    }
+
+    /// Runs a node over `msgs` until EOF and returns everything it sent.
+    pub(crate) fn run_script(
+        workload: Box<dyn Workload>,
+        msgs: &[impl AsRef<str>],
+    ) -> Vec<Message> {
+        run_to_end(NodeBuilder::new(script(msgs), Vec::new()).workload(workload))
+    }
+
+    /// Runs a node over `msgs` the way Maelstrom's clients talk to it, sending each message
+    /// only once the one before it was answered, and returns everything it sent.
+    pub(crate) fn run_in_turn(
+        workload: Box<dyn Workload>,
+        msgs: &[impl AsRef<str>],
+    ) -> Vec<Message> {
+        let (input, mut writer) = io::pipe().unwrap();
+        let node = NodeBuilder::new(input, Vec::new())
+            .workload(workload)
+            .build();
+        let output = node.output.clone();
+        let sent = move || -> Vec<Message> { sent_messages(output.lock().unwrap().get_ref()) };
+        let sent_by_node = sent.clone();
+
+        let msgs: Vec<String> = msgs.iter().map(|msg| msg.as_ref().to_string()).collect();
+        let client = std::thread::spawn(move || {
+            for msg in msgs {
+                let request: Message = serde_json::from_str(&msg).unwrap();
+                writeln!(writer, "{msg}").unwrap();
+
+                let deadline = Instant::now() + Duration::from_secs(2);
+                while !sent_by_node().iter().any(|reply| {
+                    reply.dst == request.src && reply.body.in_reply_to == request.body.msg_id
+                }) {
+                    assert!(Instant::now() < deadline, "no reply to {msg}");
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+
+        node.run();
+        client.join().unwrap();
+
+        sent()
+    }
+
+    /// Builds the node, runs it until its input ends and returns everything it sent.
+    pub(crate) fn run_to_end<Input: Read + Send + 'static>(
+        builder: NodeBuilder<Input, Vec<u8>>,
+    ) -> Vec<Message> {
+        let node = builder.build();
+        let output = node.output.clone();
+
+        node.run();
+
+        let output = output.lock().unwrap();
+        sent_messages(output.get_ref())
+    }
+
+    /// The messages in the output of a node.
+    pub(crate) fn sent_messages<P: NodePayload>(output: &[u8]) -> Vec<Message<P>> {
+        output
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    fn init(node: &mut TestNode) {
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string()],
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn generate_returns_distinct_ids() {
+        let mut node: Node<Empty, Vec<u8>, GeneratePayload> =
+            Node::new(empty(), Vec::new(), Box::<UniqueIds>::default());
+        node.proceed_message(message(GeneratePayload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string()],
+        }))
+        .unwrap();
+
+        let Ok(GeneratePayload::GenerateOk { id: first }) =
+            node.proceed_message(message(GeneratePayload::Generate))
+        else {
+            panic!("expected generate_ok");
+        };
+        let Ok(GeneratePayload::GenerateOk { id: second }) =
+            node.proceed_message(message(GeneratePayload::Generate))
+        else {
+            panic!("expected generate_ok");
+        };
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn generate_node_only_speaks_its_own_payloads() {
+        let input = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":2}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"hi"}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"ping","msg_id":4}}"#,
+        ]
+        .join("\n");
+        let node: Node<_, _, GeneratePayload> = Node::new(
+            Cursor::new(input.into_bytes()),
+            Vec::new(),
+            Box::<UniqueIds>::default(),
+        );
+        let output = node.output.clone();
+
+        node.run();
+
+        let output = output.lock().unwrap();
+        let replies: HashMap<u64, GeneratePayload> =
+            sent_messages::<GeneratePayload>(output.get_ref())
+                .into_iter()
+                .filter_map(|message| Some((message.body.in_reply_to?, message.body.payload)))
+                .collect();
+
+        assert!(matches!(replies[&1], GeneratePayload::InitOk));
+        assert!(matches!(replies[&2], GeneratePayload::GenerateOk { .. }));
+        assert!(matches!(
+            replies[&3],
+            GeneratePayload::Error {
+                code: MaelstromError::NotSupported,
+                ..
+            }
+        ));
+        assert!(matches!(replies[&4], GeneratePayload::Pong));
+    }
+
+    #[test]
+    fn txn_interleaves_reads_and_writes_in_order() {
+        let mut node = test_node(Box::<Txn>::default());
+        init(&mut node);
+
+        let txn = vec![
+            ("r".to_string(), 1, None),
+            ("w".to_string(), 1, Some(10)),
+            ("r".to_string(), 1, None),
+            ("w".to_string(), 2, Some(20)),
+            ("w".to_string(), 1, Some(11)),
+            ("r".to_string(), 2, None),
+            ("r".to_string(), 1, None),
+        ];
+
+        let Ok(Payload::TxnOk { txn }) = node.proceed_message(message(Payload::Txn { txn })) else {
+            panic!("expected txn_ok");
+        };
+
+        assert_eq!(
+            txn,
+            vec![
+                ("r".to_string(), 1, None),
+                ("w".to_string(), 1, Some(10)),
+                ("r".to_string(), 1, Some(10)),
+                ("w".to_string(), 2, Some(20)),
+                ("w".to_string(), 1, Some(11)),
+                ("r".to_string(), 2, Some(20)),
+                ("r".to_string(), 1, Some(11)),
+            ]
+        );
+    }
+
+    #[test]
+    fn txn_reads_what_an_earlier_txn_wrote_to_the_same_key() {
+        let sent = run_script(
+            Box::<Txn>::default(),
+            &[
+                r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":2,"txn":[["w",1,5]]}}"#,
+                r#"{"src":"c2","dest":"n1","body":{"type":"txn","msg_id":2,"txn":[["r",1,null],["w",1,6]]}}"#,
+            ],
+        );
+
+        let txn_of = |client: &str| {
+            sent.iter()
+                .find(|message| message.dst == client && message.body.in_reply_to == Some(2))
+                .map(|message| match &message.body.payload {
+                    Payload::TxnOk { txn } => txn.clone(),
+                    payload => panic!("expected txn_ok, got {payload:?}"),
+                })
+                .unwrap()
+        };
+
+        assert_eq!(txn_of("c1"), vec![("w".to_string(), 1, Some(5))]);
+        assert_eq!(
+            txn_of("c2"),
+            vec![("r".to_string(), 1, Some(5)), ("w".to_string(), 1, Some(6))]
+        );
+    }
+
+    #[test]
+    fn kafka_send_then_poll_round_trip() {
+        let storage = Storage::run_local().unwrap();
+        let kafka = Kafka::new(AsyncStorageClient::new(storage.addr()));
+
+        let replies = run_in_turn(
+            Box::new(kafka),
+            &[
+                r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":2,"key":"k1","msg":10}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":3,"key":"k1","msg":20}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":4,"offsets":{"k1":0}}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":5,"offsets":{"k1":0},"max_msgs":1}}"#,
+            ],
+        );
+
+        assert!(matches!(replies[0].body.payload, Payload::InitOk));
+        assert!(matches!(
+            replies[1].body.payload,
+            Payload::SendOk { offset: 0 }
+        ));
+        assert!(matches!(
+            replies[2].body.payload,
+            Payload::SendOk { offset: 1 }
+        ));
+
+        let Payload::PollOk { messages, .. } = &replies[3].body.payload else {
+            panic!("expected poll_ok");
+        };
+        assert_eq!(
+            messages["k1"],
+            vec![
+                LogEntry {
+                    offset: 0,
+                    value: 10
+                },
+                LogEntry {
+                    offset: 1,
+                    value: 20
+                }
+            ]
+        );
+        assert_eq!(replies[3].body.in_reply_to, Some(4));
+
+        let Payload::PollOk { messages, .. } = &replies[4].body.payload else {
+            panic!("expected poll_ok");
+        };
+        assert_eq!(
+            messages["k1"],
+            vec![LogEntry {
+                offset: 0,
+                value: 10
+            }]
+        );
+    }
+
+    #[test]
+    fn message_from_unknown_peer_is_rejected() {
+        let mut node = test_node(Box::new(Echo));
+        init(&mut node);
+
+        let mut from_unknown = message(Payload::Echo {
+            echo: "hi".to_string(),
+        });
+        from_unknown.src = "n7".to_string();
+
+        assert!(matches!(
+            node.proceed_message(from_unknown),
+            Err(NodeError::UnknownSource(src)) if src == "n7"
+        ));
+    }
+
+    #[test]
+    fn creating_an_existing_key_fails() {
+        let mut node = test_node(Box::<KeyValue>::default());
+        init(&mut node);
+
+        let create = || {
+            message(Payload::Create {
+                key: "k1".into(),
+                value: 1.into(),
+            })
+        };
+
+        assert!(matches!(
+            node.proceed_message(create()),
+            Ok(Payload::CreateOk)
+        ));
+        assert!(matches!(
+            node.proceed_message(create()),
+            Err(NodeError::KeyAlreadyExists)
+        ));
+    }
+
+    #[test]
+    fn lin_kv_round_trips_json_keys_and_values() {
+        let replies = run_script(
+            Box::<KeyValue>::default(),
+            &[
+                r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2,"key":3}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"write","msg_id":3,"key":3,"value":{"a":[1,2]}}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":4,"key":3,"from":{"a":[1]},"to":4}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":5,"key":3,"from":{"a":[1,2]},"to":4}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6,"key":3}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":7,"key":"3"}}"#,
+            ],
+        );
+
+        let error_code = |reply: &Message| match &reply.body.payload {
+            Payload::Error { code, .. } => Some(code.clone()),
+            _ => None,
+        };
+
+        assert!(matches!(
+            error_code(&replies[1]),
+            Some(MaelstromError::KeyDoesNotExist)
+        ));
+        assert!(matches!(replies[2].body.payload, Payload::WriteOk));
+        assert!(matches!(
+            error_code(&replies[3]),
+            Some(MaelstromError::PreconditionFailed)
+        ));
+        assert!(matches!(replies[4].body.payload, Payload::CasOk));
+        assert!(matches!(
+            &replies[5].body.payload,
+            Payload::ReadOk(ReadResult::Value { value }) if *value == 4
+        ));
+        assert!(matches!(
+            error_code(&replies[6]),
+            Some(MaelstromError::KeyDoesNotExist)
+        ));
+    }
+
+    #[test]
+    fn repeated_init_with_same_id_is_acknowledged() {
+        let mut node = test_node(Box::new(Echo));
+        init(&mut node);
+
+        let repeated = node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string()],
+        }));
+
+        assert!(matches!(repeated, Ok(Payload::InitOk)));
+    }
+
+    #[test]
+    fn repeated_init_with_other_id_is_rejected() {
+        let mut node = test_node(Box::new(Echo));
+        init(&mut node);
+
+        let repeated = node.proceed_message(message(Payload::Init {
+            node_id: "n2".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }));
+
+        let Err(err) = repeated else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err, NodeError::UnacceptablePayloadForState(..)));
+
+        let Payload::Error { text, .. } = node.wrap_err(err) else {
+            panic!("expected an error payload");
+        };
+        assert_eq!(text, "Unacceptable payload, node is initialized as n1");
+
+        assert_eq!(
+            node.state,
+            NodeState::Initialized {
+                id: "n1".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn kafka_send_with_same_dedup_id_appends_once() {
+        let storage = Storage::run_local().unwrap();
+        let kafka = Kafka::new(AsyncStorageClient::new(storage.addr()));
+
+        let replies = run_in_turn(
+            Box::new(kafka),
+            &[
+                r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":2,"key":"k1","msg":10,"dedup_id":"a"}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":3,"key":"k1","msg":10,"dedup_id":"a"}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":4,"offsets":{"k1":0}}}"#,
+            ],
+        );
+
+        assert!(matches!(
+            replies[1].body.payload,
+            Payload::SendOk { offset: 0 }
+        ));
+        assert!(matches!(
+            replies[2].body.payload,
+            Payload::SendOk { offset: 0 }
+        ));
+
+        let Payload::PollOk { messages, .. } = &replies[3].body.payload else {
+            panic!("expected poll_ok");
+        };
+        assert_eq!(messages["k1"].len(), 1);
+    }
+
+    #[test]
+    fn kafka_poll_reads_storage_in_bounded_chunks() {
+        let storage = Storage::run_local().unwrap();
+        let mut client = StorageClient::connect(storage.addr()).unwrap();
+        for value in 0..150usize {
+            client.store("k1", &value).unwrap();
+        }
+
+        let kafka = Kafka::new(AsyncStorageClient::new(storage.addr()));
+        let replies = run_in_turn(
+            Box::new(kafka),
+            &[
+                r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"commit_offsets","msg_id":2,"offsets":{"k1":100}}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":3,"offsets":{"k1":100},"max_msgs":30}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":4,"offsets":{"k1":0}}}"#,
+            ],
+        );
+
+        let offsets = |reply: &Message| match &reply.body.payload {
+            Payload::PollOk { messages, .. } => messages["k1"]
+                .iter()
+                .map(|entry| entry.offset)
+                .collect::<Vec<_>>(),
+            _ => panic!("expected poll_ok"),
+        };
+
+        assert_eq!(offsets(&replies[2]), (100..130).collect::<Vec<_>>());
+        assert_eq!(offsets(&replies[3]), (0..150).collect::<Vec<_>>());
+
+        // One range request for the first poll, three chunks for the second.
+        assert_eq!(client.stats().unwrap().gets, 4);
+    }
+
+    #[test]
+    fn kafka_poll_below_retention_is_truncated() {
+        let storage = Storage::run_local().unwrap();
+        let kafka = Kafka::new(AsyncStorageClient::new(storage.addr())).with_retention(2);
+
+        let replies = run_in_turn(
+            Box::new(kafka),
+            &[
+                r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":2,"key":"k1","msg":10}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":3,"key":"k1","msg":20}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":4,"key":"k1","msg":30}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":5,"offsets":{"k1":0}}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":6,"offsets":{"k1":2}}}"#,
+            ],
+        );
+
+        assert!(matches!(
+            replies[3].body.payload,
+            Payload::SendOk { offset: 2 }
+        ));
+
+        let Payload::PollOk {
+            messages,
+            truncated,
+        } = &replies[4].body.payload
+        else {
+            panic!("expected poll_ok");
+        };
+        assert_eq!(
+            messages["k1"],
+            vec![
+                LogEntry {
+                    offset: 1,
+                    value: 20
+                },
+                LogEntry {
+                    offset: 2,
+                    value: 30
+                }
+            ]
+        );
+        assert_eq!(truncated.get("k1"), Some(&1));
+
+        let Payload::PollOk { truncated, .. } = &replies[5].body.payload else {
+            panic!("expected poll_ok");
+        };
+        assert!(truncated.is_empty());
+    }
+
+    #[test]
+    fn kafka_poll_committed_stops_at_the_committed_offset() {
+        let storage = Storage::run_local().unwrap();
+        let kafka = Kafka::new(AsyncStorageClient::new(storage.addr()));
+
+        let replies = run_in_turn(
+            Box::new(kafka),
+            &[
+                r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":2,"key":"k1","msg":10}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":3,"key":"k1","msg":11}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":4,"key":"k1","msg":12}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":5,"key":"k2","msg":20}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"commit_offsets","msg_id":6,"offsets":{"k1":1}}}"#,
+                r#"{"src":"c1","dest":"n1","body":{"type":"poll_committed","msg_id":7,"offsets":{"k1":0,"k2":0}}}"#,
+            ],
+        );
+
+        let Payload::PollOk { messages, .. } = &replies[6].body.payload else {
+            panic!("expected poll_ok");
+        };
+
+        let k1: Vec<usize> = messages["k1"].iter().map(|entry| entry.value).collect();
+        assert_eq!(k1, [10, 11]);
+        assert!(!messages.contains_key("k2"));
+    }
+
+    #[test]
+    fn kafka_commit_for_missing_key_is_skipped_or_refused() {
+        let script = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":2,"key":"k1","msg":10}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"commit_offsets","msg_id":3,"offsets":{"k1":0,"k2":0}}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"list_committed_offsets","msg_id":4,"keys":["k1","k2"]}}"#,
+        ];
+
+        let storage = Storage::run_local().unwrap();
+        let lenient = Kafka::new(AsyncStorageClient::new(storage.addr()));
+        let replies = run_in_turn(Box::new(lenient), &script);
+
+        let Payload::CommitOffsetsOk { skipped } = &replies[2].body.payload else {
+            panic!("expected commit_offsets_ok");
+        };
+        assert_eq!(skipped, &vec!["k2".to_string()]);
+
+        let Payload::ListCommittedOffsetsOk { offsets } = &replies[3].body.payload else {
+            panic!("expected list_committed_offsets_ok");
+        };
+        assert_eq!(offsets, &BTreeMap::from([("k1".to_string(), 0)]));
+
+        let storage = Storage::run_local().unwrap();
+        let strict = Kafka::new(AsyncStorageClient::new(storage.addr())).with_strict_commits();
+        let replies = run_in_turn(Box::new(strict), &script);
+
+        assert!(matches!(
+            replies[2].body.payload,
+            Payload::Error {
+                code: MaelstromError::KeyDoesNotExist,
+                ..
+            }
+        ));
+
+        let Payload::ListCommittedOffsetsOk { offsets } = &replies[3].body.payload else {
+            panic!("expected list_committed_offsets_ok");
+        };
+        assert!(offsets.is_empty());
+    }
+
+    #[test]
+    fn request_before_init_is_temporarily_unavailable() {
+        let mut node = test_node(Box::new(Echo));
+
+        let error = node
+            .proceed_message(message(Payload::Echo {
+                echo: "hi".to_string(),
+            }))
+            .unwrap_err();
+
+        assert!(matches!(
+            node.wrap_err(error),
+            Payload::Error {
+                code: MaelstromError::TemporarilyUnavailable,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn counter_reads_its_own_adds() {
+        let mut node = test_node(Box::<GCounter>::default());
+        init(&mut node);
+
+        node.proceed_message(message(Payload::Add { delta: 5 }))
+            .unwrap();
+        node.proceed_message(message(Payload::CounterGossip {
+            increments: HashMap::from([("n1".to_string(), 2)]),
+            decrements: HashMap::new(),
+            seq: None,
+        }))
+        .unwrap();
+
+        let Ok(Payload::ReadOk(ReadResult::Value { value })) =
+            node.proceed_message(message(Payload::Read { key: None }))
+        else {
+            panic!("expected read_ok");
+        };
+
+        assert!(value.as_i64().unwrap() >= 5);
+    }
+
+    #[test]
+    fn concurrent_txn_writes_conflict() {
+        let mut node = test_node(Box::<Txn>::default());
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }))
+        .unwrap();
+
+        let txn = |txn: Vec<(&str, usize, Option<usize>)>| {
+            message(Payload::Txn {
+                txn: txn
+                    .into_iter()
+                    .map(|(op, key, value)| (op.to_string(), key, value))
+                    .collect(),
+            })
+        };
+        let read = |node: &mut TestNode| {
+            let Ok(Payload::TxnOk { txn }) =
+                node.proceed_message(txn(vec![("r", 1, None), ("r", 2, None)]))
+            else {
+                panic!("expected txn_ok");
+            };
+
+            txn.into_iter()
+                .map(|(_, _, value)| value)
+                .collect::<Vec<_>>()
+        };
+
+        node.proceed_message(txn(vec![("w", 1, Some(10))])).unwrap();
+        node.proceed_message(txn(vec![("w", 1, Some(11))])).unwrap();
+
+        // n2 wrote having seen only the first local write: concurrent with the second, and
+        // the smaller clock.
+        let mut replicated = message(Payload::TxnReplicate {
+            writes: vec![(1, 20), (2, 30)],
+            clock: BTreeMap::from([("n1".to_string(), 1), ("n2".to_string(), 1)]),
+        });
+        replicated.src = "n2".to_string();
+        assert!(matches!(
+            node.proceed_message(replicated),
+            Err(NodeError::TxnConflict)
+        ));
+        assert_eq!(read(&mut node), vec![Some(11), None]);
+
+        assert!(matches!(
+            node.proceed_message(txn(vec![("w", 2, Some(5)), ("x", 1, None)])),
+            Err(NodeError::IllegalPayload)
+        ));
+        assert_eq!(read(&mut node), vec![Some(11), None]);
+
+        // The conflict is the replicated transaction's alone, later ones go through.
+        node.proceed_message(txn(vec![("w", 1, Some(12))])).unwrap();
+        assert_eq!(read(&mut node), vec![Some(12), None]);
+    }
+
+    #[test]
+    fn validate_leaves_node_untouched() {
+        let mut node = test_node(Box::<Txn>::default());
+
+        let init_message = message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string()],
+        });
+        assert!(node.validate(&init_message).is_ok());
+        assert!(matches!(
+            node.validate(&message(Payload::Add { delta: 1 })),
+            Err(NodeError::NotInitialized)
+        ));
+        assert_eq!(node.state, NodeState::Created);
+
+        init(&mut node);
+
+        let mut foreign = message(Payload::Add { delta: 1 });
+        foreign.dst = "n2".to_string();
+        assert!(matches!(
+            node.validate(&foreign),
+            Err(NodeError::NodeIdMismatch(..))
+        ));
+        assert!(matches!(
+            node.validate(&message(Payload::InitOk)),
+            Err(NodeError::IllegalPayloadType)
+        ));
+
+        let write = message(Payload::Txn {
+            txn: vec![("w".to_string(), 1, Some(1))],
+        });
+        assert!(node.validate(&write).is_ok());
+
+        let read = node.proceed_message(message(Payload::Txn {
+            txn: vec![("r".to_string(), 1, None)],
+        }));
+        assert!(matches!(
+            read,
+            Ok(Payload::TxnOk { txn }) if txn[0].2.is_none()
+        ));
+    }
+
+    #[test]
+    fn counter_nets_increments_and_decrements_across_nodes() {
+        let ids = vec!["n1".to_string(), "n2".to_string()];
+        let mut nodes: Vec<TestNode> = ids
+            .iter()
+            .map(|id| {
+                let mut node = test_node(Box::new(GCounter::with_gossip_interval(Duration::ZERO)));
+                let mut init = message(Payload::Init {
+                    node_id: id.clone(),
+                    node_ids: ids.clone(),
+                });
+                init.dst = id.clone();
+                node.proceed_message(init).unwrap();
+                node
+            })
+            .collect();
+
+        let add = |node: &mut TestNode, dst: &str, delta| {
+            let mut add = message(Payload::Add { delta });
+            add.dst = dst.to_string();
+            node.proceed_message(add).unwrap();
+        };
+        add(&mut nodes[0], "n1", 5);
+        add(&mut nodes[1], "n2", -3);
+
+        let (workload, mut ctx) = nodes[1].split();
+        workload.tick(&mut ctx);
+
+        let gossip: Vec<Message> = sent_messages(nodes[1].output.lock().unwrap().get_ref());
+        assert_eq!(gossip.len(), 1);
+
+        for message in gossip {
+            nodes[0].proceed_message(message).unwrap();
+        }
+
+        let Ok(Payload::ReadOk(ReadResult::Value { value })) =
+            nodes[0].proceed_message(message(Payload::Read { key: None }))
+        else {
+            panic!("expected read_ok");
+        };
+
+        assert_eq!(value.as_i64(), Some(2));
+    }
+
+    #[test]
+    fn counter_gossip_fanout_rotates_through_every_peer() {
+        let mut counter = GCounter::with_gossip_interval(Duration::ZERO);
+        counter.gossip_fanout = 1;
+
+        let mut node = test_node(Box::new(counter));
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: (1..=5).map(|i| format!("n{i}")).collect(),
+        }))
+        .unwrap();
+
+        let sent = |node: &TestNode| -> Vec<String> {
+            sent_messages(node.output.lock().unwrap().get_ref())
+                .into_iter()
+                .filter(|message| matches!(message.body.payload, Payload::CounterGossip { .. }))
+                .map(|message| message.dst)
+                .collect()
+        };
+
+        // A new add every round keeps the maps changing, each round still reaches one peer.
+        for round in 1..=4 {
+            node.proceed_message(message(Payload::Add { delta: 1 }))
+                .unwrap();
+            node.tick();
+
+            assert_eq!(sent(&node).len(), round);
+        }
+
+        let reached: HashSet<String> = sent(&node).into_iter().collect();
+        assert_eq!(reached.len(), 4);
+    }
+
+    #[test]
+    fn seq_kv_counter_retries_an_add_that_lost_its_cas() {
+        let (input, mut writer) = io::pipe().unwrap();
+        let node = NodeBuilder::new(input, Vec::new())
+            .workload(Box::<SeqKvCounter>::default())
+            .build();
+        let output = node.output.clone();
+        let sent = move || -> Vec<Message> { sent_messages(output.lock().unwrap().get_ref()) };
+        let sent_by_node = sent.clone();
+
+        // Plays the clients and seq-kv, answering the node's requests in batches. Both adds
+        // read the missing total in the first batch, so one of their swaps has to fail.
+        let peer = std::thread::spawn(move || {
+            let mut kv: HashMap<String, serde_json::Value> = HashMap::new();
+            let mut answered = 0;
+            let mut serve_until =
+                |writer: &mut io::PipeWriter, done: &dyn Fn(&[Message]) -> bool| {
+                    let deadline = Instant::now() + Duration::from_secs(2);
+
+                    loop {
+                        std::thread::sleep(Duration::from_millis(20));
+
+                        let sent = sent_by_node();
+                        if done(&sent) {
+                            return;
+                        }
+                        assert!(Instant::now() < deadline, "seq-kv requests never settled");
+
+                        let requests: Vec<&Message> = sent
+                            .iter()
+                            .filter(|message| message.dst == SEQ_KV)
+                            .collect();
+                        for request in &requests[answered..] {
+                            let key = |key: &serde_json::Value| key.as_str().unwrap().to_string();
+                            let error = |code| Payload::Error {
+                                code,
+                                text: String::new(),
+                            };
+
+                            let reply = match &request.body.payload {
+                                Payload::Read { key: Some(k) } => match kv.get(&key(k)) {
+                                    Some(value) => Payload::ReadOk(ReadResult::Value {
+                                        value: value.clone(),
+                                    }),
+                                    None => error(MaelstromError::KeyDoesNotExist),
+                                },
+                                Payload::Write { key: k, value } => {
+                                    kv.insert(key(k), value.clone());
+                                    Payload::WriteOk
+                                }
+                                Payload::Cas {
+                                    key: k,
+                                    from,
+                                    to,
+                                    create_if_not_exists,
+                                } => match kv.get(&key(k)) {
+                                    Some(value) if value != from => {
+                                        error(MaelstromError::PreconditionFailed)
+                                    }
+                                    None if *create_if_not_exists != Some(true) => {
+                                        error(MaelstromError::KeyDoesNotExist)
+                                    }
+                                    _ => {
+                                        kv.insert(key(k), to.clone());
+                                        Payload::CasOk
+                                    }
+                                },
+                                payload => panic!("unexpected seq-kv request {payload:?}"),
+                            };
+
+                            let reply = Message {
+                                src: SEQ_KV.to_string(),
+                                dst: "n1".to_string(),
+                                body: Body {
+                                    msg_id: None,
+                                    in_reply_to: request.body.msg_id,
+                                    ts: None,
+                                    payload: reply,
+                                },
+                            };
+                            writeln!(writer, "{}", serde_json::to_string(&reply).unwrap()).unwrap();
+                        }
+                        answered = requests.len();
+                    }
+                };
+            let replied = |client: &'static str, msg_id| {
+                move |sent: &[Message]| {
+                    sent.iter().any(|message| {
+                        message.dst == client && message.body.in_reply_to == Some(msg_id)
+                    })
+                }
+            };
+
+            writeln!(
+                writer,
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}}}"#
+            )
+            .unwrap();
+            writeln!(
+                writer,
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"add","msg_id":2,"delta":3}}}}"#
+            )
+            .unwrap();
+            writeln!(
+                writer,
+                r#"{{"src":"c2","dest":"n1","body":{{"type":"add","msg_id":2,"delta":5}}}}"#
+            )
+            .unwrap();
+            serve_until(&mut writer, &|sent| {
+                replied("c1", 2)(sent) && replied("c2", 2)(sent)
+            });
+
+            writeln!(
+                writer,
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"read","msg_id":3}}}}"#
+            )
+            .unwrap();
+            serve_until(&mut writer, &replied("c1", 3));
+        });
+
+        node.run();
+        peer.join().unwrap();
+
+        let sent = sent();
+        let swaps = sent
+            .iter()
+            .filter(|message| matches!(message.body.payload, Payload::Cas { .. }))
+            .count();
+        assert_eq!(swaps, 3);
+
+        let read = sent
+            .iter()
+            .find(|message| message.body.in_reply_to == Some(3))
+            .unwrap();
+        assert!(matches!(
+            &read.body.payload,
+            Payload::ReadOk(ReadResult::Value { value }) if *value == 8
+        ));
+    }
+
+    #[test]
+    fn broadcast_converges_despite_lost_messages() {
+        let clock = Arc::new(MockClock::new());
+        let ids: Vec<String> = (1..=3).map(|n| format!("n{n}")).collect();
+        let topology: HashMap<String, Vec<String>> = HashMap::from([
+            ("n1".to_string(), vec!["n2".to_string()]),
+            ("n2".to_string(), vec!["n1".to_string(), "n3".to_string()]),
+            ("n3".to_string(), vec!["n2".to_string()]),
+        ]);
+
+        let mut nodes: HashMap<String, TestNode> = ids
+            .iter()
+            .enumerate()
+            .map(|(seed, id)| {
+                let mut node = NodeBuilder::new(empty(), Vec::new())
+                    .workload(Box::<Broadcast>::default())
+                    .clock(clock.clone())
+                    .faults(
+                        FaultInjector::new(seed as u64)
+                            .drop(0.3)
+                            .duplicate(0.1)
+                            .delay(0.1, 3),
+                    )
+                    .build();
+
+                let to_node = |payload| {
+                    let mut message = message(payload);
+                    message.dst = id.clone();
+                    message
+                };
+                node.proceed_message(to_node(Payload::Init {
+                    node_id: id.clone(),
+                    node_ids: ids.clone(),
+                }))
+                .unwrap();
+                node.proceed_message(to_node(Payload::Topology {
+                    topology: topology.clone(),
+                }))
+                .unwrap();
+                for value in 0..10 {
+                    node.proceed_message(to_node(Payload::Broadcast {
+                        message: value * 3 + seed,
+                    }))
+                    .unwrap();
+                }
+
+                (id.clone(), node)
+            })
+            .collect();
+
+        for _ in 0..300 {
+            clock.advance(Duration::from_millis(100));
+
+            let mut in_flight = Vec::new();
+            for node in nodes.values_mut() {
+                node.tick();
+
+                let sent = std::mem::take(node.output.lock().unwrap().get_mut());
+                in_flight.extend(sent_messages::<Payload>(&sent));
+            }
+
+            for message in in_flight {
+                if let Some(node) = nodes.get_mut(&message.dst) {
+                    node.handle_message(message).unwrap();
+                }
+            }
+        }
+
+        for (id, node) in &mut nodes {
+            let mut read = message(Payload::Read { key: None });
+            read.dst = id.clone();
+            let Ok(Payload::ReadOk(ReadResult::Messages { mut messages })) =
+                node.proceed_message(read)
+            else {
+                panic!("expected read_ok");
+            };
+            messages.sort();
+
+            assert_eq!(messages, (0..30).collect::<Vec<_>>(), "{id}");
+        }
+    }
+
+    #[test]
+    fn one_ack_clears_a_whole_broadcast_batch() {
+        let clock = Arc::new(MockClock::new());
+        let mut node: TestNode = NodeBuilder::new(empty(), Vec::new())
+            .workload(Box::<Broadcast>::default())
+            .clock(clock.clone())
+            .build();
+
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }))
+        .unwrap();
+        node.proceed_message(message(Payload::Topology {
+            topology: HashMap::from([("n1".to_string(), vec!["n2".to_string()])]),
+        }))
+        .unwrap();
+        for value in 0..100 {
+            node.proceed_message(message(Payload::Broadcast { message: value }))
+                .unwrap();
+        }
+
+        let batches = |node: &TestNode| -> Vec<Message> {
+            sent_messages(node.output.lock().unwrap().get_ref())
+                .into_iter()
+                .filter(|message| matches!(message.body.payload, Payload::BroadcastBatch { .. }))
+                .collect()
+        };
+
+        node.tick();
+        clock.advance(Duration::from_millis(130));
+        node.tick();
+
+        let sent = batches(&node);
+        let [batch] = sent.as_slice() else {
+            panic!("expected a single batch, got {sent:?}");
+        };
+        assert!(matches!(
+            &batch.body.payload,
+            Payload::BroadcastBatch { messages, seq: Some(0) } if messages.len() == 100
+        ));
+
+        // n2 already had some of the values, the ack still covers all of them.
+        let mut ack = message(Payload::BroadcastBatchOk { fresh: 60 });
+        ack.src = "n2".to_string();
+        ack.body.in_reply_to = batch.body.msg_id;
+        assert!(node.build_reply(ack).is_none());
+
+        for _ in 0..10 {
+            clock.advance(Duration::from_secs(1));
+
+            let mut pong = message(Payload::Pong);
+            pong.src = "n2".to_string();
+            node.proceed_message(pong).unwrap();
+
+            node.tick();
+        }
+        assert_eq!(batches(&node).len(), 1);
+
+        // The other way around, only values new to the receiver count as fresh.
+        let mut batch = message(Payload::BroadcastBatch {
+            messages: vec![5, 500],
+            seq: None,
+        });
+        batch.src = "n2".to_string();
+        assert!(matches!(
+            node.proceed_message(batch),
+            Ok(Payload::BroadcastBatchOk { fresh: 1 })
+        ));
+    }
+
+    #[test]
+    fn broadcast_batches_overtaking_earlier_ones_are_applied_in_order() {
+        let clock = Arc::new(MockClock::new());
+        let mut node: TestNode = NodeBuilder::new(empty(), Vec::new())
+            .workload(Box::<Broadcast>::default())
+            .clock(clock.clone())
+            .build();
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }))
+        .unwrap();
+
+        let deliver = |node: &mut TestNode, seq: u64, value: usize| {
+            let mut batch = message(Payload::BroadcastBatch {
+                messages: vec![value],
+                seq: Some(seq),
+            });
+            batch.src = "n2".to_string();
+            node.proceed_message(batch).unwrap();
+
+            let Ok(Payload::ReadOk(ReadResult::Messages { messages })) =
+                node.proceed_message(message(Payload::Read { key: None }))
+            else {
+                panic!("expected read_ok");
+            };
+            messages
+        };
+
+        // Acknowledged, but held back until the batch before it shows up.
+        assert_eq!(deliver(&mut node, 1, 11), Vec::<usize>::new());
+        assert_eq!(deliver(&mut node, 0, 10), [10, 11]);
+
+        // A gap that stays open is skipped once the reorder window ran out.
+        assert_eq!(deliver(&mut node, 3, 13), [10, 11]);
+        clock.advance(DEFAULT_REORDER_WINDOW);
+        node.tick();
+        assert_eq!(deliver(&mut node, 2, 12), [10, 11, 13]);
+    }
+
+    #[test]
+    fn unacknowledged_broadcast_is_retried_with_growing_gaps() {
+        let clock = Arc::new(MockClock::new());
+        let mut node: TestNode = NodeBuilder::new(empty(), Vec::new())
+            .workload(Box::<Broadcast>::default())
+            .clock(clock.clone())
+            .build();
+
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }))
+        .unwrap();
+        node.proceed_message(message(Payload::Topology {
+            topology: HashMap::from([("n1".to_string(), vec!["n2".to_string()])]),
+        }))
+        .unwrap();
+        node.proceed_message(message(Payload::Broadcast { message: 7 }))
+            .unwrap();
+
+        let batches = |node: &TestNode| {
+            sent_messages(node.output.lock().unwrap().get_ref())
+                .into_iter()
+                .filter(|message| matches!(message.body.payload, Payload::BroadcastBatch { .. }))
+                .count()
+        };
+
+        // Starts the timers; periods are jittered by up to a fifth of the interval.
+        node.tick();
+        assert_eq!(batches(&node), 0);
+
+        clock.advance(Duration::from_millis(130));
+        node.tick();
+        assert_eq!(batches(&node), 1);
+
+        // Retried 500ms after the first send, then 1s after that.
+        clock.advance(Duration::from_millis(470));
+        node.tick();
+        assert_eq!(batches(&node), 1);
+
+        clock.advance(Duration::from_millis(100));
+        node.tick();
+        assert_eq!(batches(&node), 2);
+
+        clock.advance(Duration::from_millis(900));
+        node.tick();
+        assert_eq!(batches(&node), 2);
+
+        clock.advance(Duration::from_millis(200));
+        node.tick();
+        assert_eq!(batches(&node), 3);
+    }
+
+    #[test]
+    fn init_node_ids_are_deduplicated_and_include_self() {
+        let mut node = test_node(Box::<Txn>::default());
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n2".to_string(), "n3".to_string(), "n2".to_string()],
+        }))
+        .unwrap();
+
+        assert_eq!(node.all_node_ids, vec!["n2", "n3", "n1"]);
+
+        let (_, ctx) = node.split();
+        assert_eq!(ctx.peers(), vec!["n2", "n3"]);
+    }
+
+    #[test]
+    fn never_acknowledged_broadcast_is_dead_lettered() {
+        let clock = Arc::new(MockClock::new());
+        let mut broadcast = Broadcast::default();
+        broadcast.max_attempts = 3;
+
+        let mut node: TestNode = NodeBuilder::new(empty(), Vec::new())
+            .workload(Box::new(broadcast))
+            .clock(clock.clone())
+            .build();
+
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }))
+        .unwrap();
+        node.proceed_message(message(Payload::Topology {
+            topology: HashMap::from([("n1".to_string(), vec!["n2".to_string()])]),
+        }))
+        .unwrap();
+        node.proceed_message(message(Payload::Broadcast { message: 7 }))
+            .unwrap();
+
+        let batches = |node: &TestNode| {
+            sent_messages(node.output.lock().unwrap().get_ref())
+                .into_iter()
+                .filter(|message| matches!(message.body.payload, Payload::BroadcastBatch { .. }))
+                .count()
+        };
+
+        node.tick();
+
+        // n2 keeps answering pings, so it is never suspected, but never acknowledges.
+        for _ in 0..10 {
+            clock.advance(Duration::from_millis(600));
+
+            let mut pong = message(Payload::Pong);
+            pong.src = "n2".to_string();
+            node.proceed_message(pong).unwrap();
+
+            node.tick();
+        }
+
+        assert_eq!(batches(&node), 3);
+    }
+
+    #[test]
+    fn peer_answering_only_rpcs_is_seen_and_counted() {
+        let clock = Arc::new(MockClock::new());
+        let mut node: TestNode = NodeBuilder::new(empty(), Vec::new())
+            .clock(clock.clone())
+            .build();
+
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }))
+        .unwrap();
+
+        let rt = Runtime::new().unwrap();
+        let network = node.network.clone();
+        let rpc = rt.spawn(async move {
+            network
+                .rpc_with_timeout("n2", Payload::CounterRead, Duration::from_secs(5))
+                .await
+        });
+
+        let request = loop {
+            let request = sent_messages::<Payload>(node.output.lock().unwrap().get_ref())
+                .into_iter()
+                .find(|message| message.dst == "n2");
+
+            match request {
+                Some(request) => break request,
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+        };
+
+        // n2 says nothing but the reply, which goes straight to the rpc.
+        clock.advance(SUSPECT_TIMEOUT * 2);
+        let reply = format!(
+            r#"{{"src":"n2","dest":"n1","body":{{"type":"counter_read_ok","in_reply_to":{},"increments":{{}},"decrements":{{}}}}}}"#,
+            request.body.msg_id.unwrap()
+        );
+        assert!(node.network.route_reply(reply).is_none());
+        assert!(rt.block_on(rpc).unwrap().is_ok());
+
+        node.tick();
+
+        assert!(!node.liveness.is_suspect("n2", clock.now()));
+        assert_eq!(node.network.routed().get("counter_read_ok"), Some(&1));
+    }
+
+    #[test]
+    fn retransmission_of_a_deferred_request_is_answered_once() {
+        let add = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":1}}"#;
+
+        // seq-kv never answers, the add is deferred until its read times out.
+        let sent = run_script(
+            Box::<SeqKvCounter>::default(),
+            &[
+                r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#,
+                add,
+                add,
+            ],
+        );
+
+        let reads = sent.iter().filter(|message| message.dst == SEQ_KV).count();
+        assert_eq!(reads, 1);
+
+        let replies = sent
+            .iter()
+            .filter(|message| message.dst == "c1" && message.body.in_reply_to == Some(2))
+            .count();
+        assert_eq!(replies, 1);
+    }
+
+    #[test]
+    fn dropped_node_flushes_held_back_output() {
+        let node: TestNode = NodeBuilder::new(empty(), Vec::new())
+            .flush_policy(FlushPolicy::EveryN(100))
+            .build();
+        let output = node.output.clone();
+
+        node.send_to_network(message(Payload::EchoOk {
+            echo: "hi".to_string(),
+        }))
+        .unwrap();
+        assert!(output.lock().unwrap().get_ref().is_empty());
+
+        drop(node);
+
+        let output = output.lock().unwrap();
+        let sent: Message = serde_json::from_slice(output.get_ref()).unwrap();
+        assert!(matches!(sent.body.payload, Payload::EchoOk { .. }));
+    }
+
+    #[test]
+    fn only_kafka_needs_storage() {
+        for name in ["echo", "generate", "broadcast", "counter", "lin-kv", "txn"] {
+            assert!(!needs_storage(Some(name)), "{name}");
+        }
+
+        assert!(needs_storage(Some("kafka")));
+        assert!(needs_storage(None));
+    }
+
+    #[test]
+    fn retransmitted_add_is_applied_once() {
+        let mut node = test_node(Box::<GCounter>::default());
+        init(&mut node);
+
+        let mut add = message(Payload::Add { delta: 3 });
+        add.body.msg_id = Some(2);
+
+        for _ in 0..2 {
+            let reply = node.build_reply(add.clone()).unwrap();
+            assert!(matches!(reply.body.payload, Payload::AddOk));
+            assert_eq!(reply.body.in_reply_to, Some(2));
+        }
+
+        let mut read = message(Payload::Read { key: None });
+        read.body.msg_id = Some(3);
+
+        let reply = node.build_reply(read).unwrap();
+        assert!(matches!(
+            reply.body.payload,
+            Payload::ReadOk(ReadResult::Value { value }) if value == 3
+        ));
+    }
+
+    #[test]
+    fn only_client_replies_are_cached() {
+        let mut node = test_node(Box::<GCounter>::default());
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string()],
+        }))
+        .unwrap();
+
+        let mut add = message(Payload::Add { delta: 3 });
+        add.body.msg_id = Some(2);
+        node.build_reply(add).unwrap();
+
+        let mut counter_read = message(Payload::CounterRead);
+        counter_read.src = "n2".to_string();
+        counter_read.body.msg_id = Some(2);
+        node.build_reply(counter_read).unwrap();
+
+        assert!(node.replies.get("c1", 2).is_some());
+        assert!(node.replies.get("n2", 2).is_none());
+    }
+
+    #[test]
+    fn request_without_msg_id_is_malformed() {
+        let mut node = test_node(Box::new(Echo));
+        init(&mut node);
+
+        let mut echo = message(Payload::Echo {
+            echo: "hi".to_string(),
+        });
+        echo.body.msg_id = None;
+
+        let reply = node.build_reply(echo).unwrap();
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Error {
+                code: MaelstromError::MalformedRequest,
+                ..
+            }
+        ));
+        assert_eq!(reply.body.in_reply_to, None);
+    }
+
+    #[test]
+    fn reply_without_msg_id_is_not_answered() {
+        let mut node = test_node(Box::<Broadcast>::default());
+        init(&mut node);
+
+        for payload in [
+            Payload::BroadcastOk,
+            Payload::EchoOk {
+                echo: "hi".to_string(),
+            },
+        ] {
+            let mut reply = message(payload);
+            reply.body.msg_id = None;
+            reply.body.in_reply_to = Some(1);
+
+            assert!(node.build_reply(reply).is_none());
+        }
+    }
+
+    #[test]
+    fn broadcast_forwards_only_along_the_spanning_tree() {
+        let clock = Arc::new(MockClock::new());
+        let mut node: TestNode = NodeBuilder::new(empty(), Vec::new())
+            .workload(Box::<Broadcast>::default())
+            .clock(clock.clone())
+            .build();
+
+        // A 2x3 grid; the tree rooted at n1 only connects n5 to n2.
+        let edges = [("n1", "n2"), ("n2", "n3"), ("n1", "n4"), ("n2", "n5")];
+        let edges = edges
+            .into_iter()
+            .chain([("n3", "n6"), ("n4", "n5"), ("n5", "n6")]);
+        let mut topology: HashMap<String, Vec<String>> = HashMap::new();
+        for (a, b) in edges {
+            topology
+                .entry(a.to_string())
+                .or_default()
+                .push(b.to_string());
+            topology
+                .entry(b.to_string())
+                .or_default()
+                .push(a.to_string());
+        }
+
+        let to_n5 = |payload| Message {
+            dst: "n5".to_string(),
+            ..message(payload)
+        };
+
+        node.proceed_message(to_n5(Payload::Init {
+            node_id: "n5".to_string(),
+            node_ids: (1..=6).map(|i| format!("n{i}")).collect(),
+        }))
+        .unwrap();
+        node.proceed_message(to_n5(Payload::Topology { topology }))
+            .unwrap();
+        node.proceed_message(to_n5(Payload::Broadcast { message: 7 }))
+            .unwrap();
+
+        node.tick();
+        clock.advance(Duration::from_millis(130));
+        node.tick();
+
+        let output = node.output.lock().unwrap();
+        let batches: Vec<String> = sent_messages(output.get_ref())
+            .into_iter()
+            .filter(|message| matches!(message.body.payload, Payload::BroadcastBatch { .. }))
+            .map(|message| message.dst)
+            .collect();
+
+        assert_eq!(batches, vec!["n2"]);
+    }
+
+    #[cfg(feature = "debug_api")]
+    #[test]
+    fn broadcast_provenance_keeps_the_first_sender() {
+        let mut node = test_node(Box::<Broadcast>::default());
+        node.proceed_message(message(Payload::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string(), "n2".to_string(), "n3".to_string()],
+        }))
+        .unwrap();
+
+        for (src, ts) in [("n2", 4), ("n3", 9)] {
+            let mut batch = message(Payload::BroadcastBatch {
+                messages: vec![7],
+                seq: None,
+            });
+            batch.src = src.to_string();
+            batch.body.ts = Some(ts);
+            node.proceed_message(batch).unwrap();
+        }
+
+        let Payload::DebugProvenanceOk { provenance } = node
+            .proceed_message(message(Payload::DebugProvenance))
+            .unwrap()
+        else {
+            panic!("expected debug_provenance_ok");
+        };
+
+        assert_eq!(
+            provenance,
+            BTreeMap::from([(
+                7,
+                Provenance {
+                    from: "n2".to_string(),
+                    ts: 4
+                }
+            )])
+        );
+    }
 }
@@ -0,0 +1,542 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinSet;
+
+#[cfg(test)]
+use crate::fault::FaultInjector;
+use crate::{Body, Envelope, EnvelopeBody, Message, NodeError, NodePayload, Payload};
+
+/// How many RPCs to one peer may be in flight at once by default.
+const DEFAULT_IN_FLIGHT_LIMIT: usize = 64;
+
+/// When writes to the network are flushed out of the output buffer.
+#[derive(Clone, Copy, Debug)]
+pub enum FlushPolicy {
+    /// After every message.
+    Immediate,
+    /// After every `n` messages.
+    EveryN(usize),
+    /// Whenever the interval elapses, driven by the node's event loop.
+    Interval(Duration),
+}
+
+/// The outbound half of a node: message ids, writes to the network and the RPCs still
+/// waiting for a reply. Cheap to clone, so tasks can hold their own.
+pub struct Network<P = Payload> {
+    node_id: Arc<OnceLock<String>>,
+    next_message_id: Arc<AtomicU64>,
+    /// The node's Lamport clock. It lives here rather than on the node so requests sent
+    /// from workloads and tasks are stamped too.
+    clock: Arc<AtomicU64>,
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Message<P>>>>>,
+    /// Sent messages per payload type.
+    sent: Arc<Mutex<HashMap<&'static str, u64>>>,
+    /// Replies handed to waiting RPCs per payload type. They never reach the node, which
+    /// counts everything else it receives itself.
+    routed: Arc<Mutex<HashMap<&'static str, u64>>>,
+    /// Where those replies came from since the node last took them, see
+    /// [`Self::take_repliers`].
+    repliers: Arc<Mutex<HashSet<String>>>,
+    rpc_timeout: Duration,
+    /// How often [`Self::forward_rpc`] tries a request, and how long each attempt waits.
+    forward_attempts: usize,
+    forward_timeout: Duration,
+    /// How many RPCs to one peer may wait for their replies at once. Later ones queue here
+    /// until one of those is answered or times out, rather than piling onto a slow peer.
+    in_flight_limit: usize,
+    in_flight: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+    flush_policy: FlushPolicy,
+    /// Messages written since the last flush, for [`FlushPolicy::EveryN`].
+    unflushed: Arc<AtomicUsize>,
+    /// Tampers with everything sent, see [`FaultInjector`].
+    #[cfg(test)]
+    faults: Option<Arc<Mutex<FaultInjector<P>>>>,
+    output: Arc<Mutex<dyn Write + Send>>,
+}
+
+// Derived, it would ask for `P: Clone` though only `Arc`s of it are cloned.
+impl<P> Clone for Network<P> {
+    fn clone(&self) -> Self {
+        Self {
+            node_id: self.node_id.clone(),
+            next_message_id: self.next_message_id.clone(),
+            clock: self.clock.clone(),
+            pending: self.pending.clone(),
+            sent: self.sent.clone(),
+            routed: self.routed.clone(),
+            repliers: self.repliers.clone(),
+            rpc_timeout: self.rpc_timeout,
+            forward_attempts: self.forward_attempts,
+            forward_timeout: self.forward_timeout,
+            in_flight_limit: self.in_flight_limit,
+            in_flight: self.in_flight.clone(),
+            flush_policy: self.flush_policy,
+            unflushed: self.unflushed.clone(),
+            #[cfg(test)]
+            faults: self.faults.clone(),
+            output: self.output.clone(),
+        }
+    }
+}
+
+impl<P: NodePayload> Network<P> {
+    pub fn new(output: Arc<Mutex<dyn Write + Send>>) -> Self {
+        Self {
+            node_id: Arc::new(OnceLock::new()),
+            next_message_id: Arc::new(AtomicU64::new(0)),
+            clock: Arc::new(AtomicU64::new(0)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            sent: Arc::new(Mutex::new(HashMap::new())),
+            routed: Arc::new(Mutex::new(HashMap::new())),
+            repliers: Arc::new(Mutex::new(HashSet::new())),
+            rpc_timeout: Duration::from_secs(1),
+            forward_attempts: 1,
+            forward_timeout: Duration::from_secs(1),
+            in_flight_limit: DEFAULT_IN_FLIGHT_LIMIT,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            flush_policy: FlushPolicy::Immediate,
+            unflushed: Arc::new(AtomicUsize::new(0)),
+            #[cfg(test)]
+            faults: None,
+            output,
+        }
+    }
+
+    /// Records the id this node was initialized with; requests are sent from it.
+    pub fn set_node_id(&self, node_id: &str) {
+        let _ = self.node_id.set(node_id.to_string());
+    }
+
+    pub fn node_id(&self) -> &str {
+        self.node_id.get().map_or("", String::as_str)
+    }
+
+    /// Applies to peers not sent an RPC yet, set it before the node starts.
+    #[cfg(test)]
+    pub fn set_in_flight_limit(&mut self, in_flight_limit: usize) {
+        self.in_flight_limit = in_flight_limit.max(1);
+    }
+
+    /// The in-flight slots of RPCs to `dst`.
+    fn in_flight_slots(&self, dst: &str) -> Arc<Semaphore> {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .entry(dst.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.in_flight_limit)))
+            .clone()
+    }
+
+    pub fn set_forward_retries(&mut self, attempts: usize, timeout: Duration) {
+        self.forward_attempts = attempts;
+        self.forward_timeout = timeout;
+    }
+
+    pub fn set_flush_policy(&mut self, flush_policy: FlushPolicy) {
+        self.flush_policy = flush_policy;
+    }
+
+    #[cfg(test)]
+    pub fn set_faults(&mut self, faults: FaultInjector<P>) {
+        self.faults = Some(Arc::new(Mutex::new(faults)));
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    pub fn next_message_id(&self) -> u64 {
+        self.next_message_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Advances the clock past a received timestamp, `None` counting as 0.
+    pub fn observe_clock(&self, ts: Option<u64>) {
+        let ts = ts.unwrap_or(0);
+
+        let _ = self
+            .clock
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |local| {
+                Some(local.max(ts) + 1)
+            });
+    }
+
+    /// Stamps `message` with the next clock value and writes it out.
+    pub fn send_to_network(&self, mut message: Message<P>) -> io::Result<()> {
+        message.body.ts = Some(self.clock.fetch_add(1, Ordering::Relaxed) + 1);
+
+        *self
+            .sent
+            .lock()
+            .unwrap()
+            .entry(message.body.payload.type_name())
+            .or_default() += 1;
+
+        #[cfg(test)]
+        if let Some(faults) = &self.faults {
+            let out = faults.lock().unwrap().outgoing(message);
+            return out.iter().try_for_each(|message| self.write(message));
+        }
+
+        self.write(&message)
+    }
+
+    fn write(&self, message: &Message<P>) -> io::Result<()> {
+        let mut data = serde_json::to_string(message)?;
+
+        data.push('\n');
+
+        tracing::debug!(message = data.trim_end(), "sent");
+        let mut output = self.output.lock().unwrap();
+        output.write_all(data.as_bytes())?;
+
+        let flush = match self.flush_policy {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::EveryN(n) => self.unflushed.fetch_add(1, Ordering::Relaxed) + 1 >= n,
+            FlushPolicy::Interval(_) => false,
+        };
+
+        if flush {
+            self.unflushed.store(0, Ordering::Relaxed);
+            output.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn sent(&self) -> HashMap<&'static str, u64> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn routed(&self) -> HashMap<&'static str, u64> {
+        self.routed.lock().unwrap().clone()
+    }
+
+    /// The sources of the replies routed since the last call. The node marks them as seen,
+    /// a peer only ever answering RPCs is as alive as one sending requests.
+    pub fn take_repliers(&self) -> HashSet<String> {
+        std::mem::take(&mut self.repliers.lock().unwrap())
+    }
+
+    /// Message ids of the RPCs still waiting for their reply.
+    pub fn pending_rpcs(&self) -> Vec<u64> {
+        let mut pending: Vec<u64> = self.pending.lock().unwrap().keys().copied().collect();
+        pending.sort();
+
+        pending
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.output.lock().unwrap().flush()
+    }
+
+    /// Sends `payload` to `dst` as a new request from this node and returns its `msg_id`.
+    pub fn send(&self, dst: &str, payload: P) -> io::Result<u64> {
+        let msg_id = self.next_message_id();
+
+        let message = Message {
+            src: self.node_id().to_string(),
+            dst: dst.to_string(),
+            body: Body {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                ts: None,
+                payload,
+            },
+        };
+
+        self.send_to_network(message)?;
+
+        Ok(msg_id)
+    }
+
+    /// Sends `payload` to `dst` and resolves with the reply, an `error` reply becoming
+    /// [`NodeError::Remote`]. Gives up with [`NodeError::Timeout`] after the rpc timeout.
+    pub async fn rpc(&self, dst: &str, payload: P) -> Result<P, NodeError> {
+        self.rpc_with_timeout(dst, payload, self.rpc_timeout).await
+    }
+
+    /// [`Self::rpc`] giving up after `timeout` instead of the node's rpc timeout. The
+    /// timeout starts once the request is sent, not while it is queued behind the in-flight
+    /// limit.
+    pub async fn rpc_with_timeout(
+        &self,
+        dst: &str,
+        payload: P,
+        timeout: Duration,
+    ) -> Result<P, NodeError> {
+        let slots = self.in_flight_slots(dst);
+        if slots.available_permits() == 0 {
+            tracing::warn!(
+                dst,
+                limit = self.in_flight_limit,
+                "peer saturated, queueing rpc"
+            );
+        }
+
+        // Held until the reply or the timeout, the semaphore is never closed.
+        let Ok(_slot) = slots.acquire().await else {
+            return Err(NodeError::Timeout);
+        };
+
+        let (sender, receiver) = oneshot::channel();
+
+        // Registered before sending, the reply may come back before `send` returns.
+        let msg_id = self.next_message_id();
+        self.pending.lock().unwrap().insert(msg_id, sender);
+
+        let sent = self
+            .send_to_network(Message {
+                src: self.node_id().to_string(),
+                dst: dst.to_string(),
+                body: Body {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    ts: None,
+                    payload,
+                },
+            })
+            .and_then(|_| self.flush());
+
+        if sent.is_err() {
+            self.pending.lock().unwrap().remove(&msg_id);
+            return Err(NodeError::Timeout);
+        }
+
+        let reply = tokio::time::timeout(timeout, receiver).await;
+        self.pending.lock().unwrap().remove(&msg_id);
+
+        match reply {
+            Ok(Ok(reply)) => match reply.body.payload.error_code() {
+                Some(code) => Err(NodeError::Remote(code.clone())),
+                None => Ok(reply.body.payload),
+            },
+            _ => Err(NodeError::Timeout),
+        }
+    }
+
+    /// Forwards a client's request to the nodes able to answer it, trying them in turn until
+    /// one replies. Each attempt waits the forward timeout; once all attempts timed out the
+    /// client gets [`NodeError::Timeout`] rather than hanging on a partitioned node. Error
+    /// replies are passed on as they are.
+    pub async fn forward_rpc(&self, dsts: &[String], payload: P) -> Result<P, NodeError> {
+        for dst in dsts.iter().cycle().take(self.forward_attempts) {
+            match self
+                .rpc_with_timeout(dst, payload.clone(), self.forward_timeout)
+                .await
+            {
+                Err(NodeError::Timeout) => {
+                    tracing::debug!(dst, "forwarded request timed out");
+                }
+                reply => return reply,
+            }
+        }
+
+        Err(NodeError::Timeout)
+    }
+
+    /// Sends `payload` to every node in `dsts` at once and resolves once `quorum` of them
+    /// replied with anything but an error. Fails with the last error otherwise.
+    pub async fn quorum_rpc(
+        &self,
+        dsts: &[String],
+        payload: P,
+        quorum: usize,
+    ) -> Result<(), NodeError> {
+        if quorum == 0 {
+            return Ok(());
+        }
+
+        let mut requests = JoinSet::new();
+        for dst in dsts {
+            let network = self.clone();
+            let dst = dst.clone();
+            let payload = payload.clone();
+
+            requests.spawn(async move { network.rpc(&dst, payload).await });
+        }
+
+        let mut acks = 0;
+        let mut last_err = NodeError::Timeout;
+
+        while let Some(reply) = requests.join_next().await {
+            match reply {
+                Ok(Ok(_)) => acks += 1,
+                Ok(Err(err)) => last_err = err,
+                Err(_) => {}
+            }
+
+            if acks >= quorum {
+                // The rest still get the request, their replies are just not waited for.
+                requests.detach_all();
+
+                return Ok(());
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Sends every request concurrently and collects the replies, paired with the node they
+    /// came from, until `wanted` of them arrived or `timeout` elapsed. Failed and late
+    /// requests are left out.
+    pub async fn gather_rpc(
+        &self,
+        requests: Vec<(String, P)>,
+        wanted: usize,
+        timeout: Duration,
+    ) -> Vec<(String, P)> {
+        let mut pending = JoinSet::new();
+        for (dst, payload) in requests {
+            let network = self.clone();
+
+            pending.spawn(async move {
+                let reply = network.rpc(&dst, payload).await;
+                (dst, reply)
+            });
+        }
+
+        let mut replies = Vec::new();
+
+        let _ = tokio::time::timeout(timeout, async {
+            while replies.len() < wanted {
+                let Some(joined) = pending.join_next().await else {
+                    break;
+                };
+
+                if let Ok((dst, Ok(reply))) = joined {
+                    replies.push((dst, reply));
+                }
+            }
+        })
+        .await;
+
+        // Late requests run into their own rpc timeout and clean up after themselves.
+        pending.detach_all();
+
+        replies
+    }
+
+    /// Hands `line` to the RPC waiting for it, if it is a reply to one. Returns the line
+    /// back when nobody is waiting for it.
+    pub fn route_reply(&self, line: String) -> Option<String> {
+        let Ok(Envelope {
+            body:
+                EnvelopeBody {
+                    in_reply_to: Some(in_reply_to),
+                    ..
+                },
+            ..
+        }) = serde_json::from_str(&line)
+        else {
+            return Some(line);
+        };
+
+        let Some(sender) = self.pending.lock().unwrap().remove(&in_reply_to) else {
+            return Some(line);
+        };
+
+        match serde_json::from_str::<Message<P>>(&line) {
+            Ok(reply) => {
+                self.observe_clock(reply.body.ts);
+                tracing::debug!(?reply, "rpc reply");
+
+                *self
+                    .routed
+                    .lock()
+                    .unwrap()
+                    .entry(reply.body.payload.type_name())
+                    .or_default() += 1;
+                self.repliers.lock().unwrap().insert(reply.src.clone());
+
+                let _ = sender.send(reply);
+
+                None
+            }
+            Err(_) => Some(line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn rpcs_to_a_saturated_peer_queue() {
+        let output = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let mut network = Network::new(output.clone());
+        network.set_node_id("n1");
+        network.set_in_flight_limit(1);
+
+        let sent = || output.lock().unwrap().split(|b| *b == b'\n').count() - 1;
+
+        Runtime::new().unwrap().block_on(async {
+            let rpc = |network: Network| async move {
+                network
+                    .rpc_with_timeout("n2", Payload::Ping, Duration::from_millis(50))
+                    .await
+            };
+            let first = tokio::spawn(rpc(network.clone()));
+            let second = tokio::spawn(rpc(network.clone()));
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(sent(), 1);
+
+            assert!(matches!(first.await.unwrap(), Err(NodeError::Timeout)));
+            assert!(matches!(second.await.unwrap(), Err(NodeError::Timeout)));
+        });
+
+        assert_eq!(sent(), 2);
+    }
+
+    #[test]
+    fn gather_returns_once_enough_replied() {
+        let output = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let network = Network::new(output.clone());
+        network.set_node_id("n1");
+
+        Runtime::new().unwrap().block_on(async {
+            let gathering = tokio::spawn({
+                let network = network.clone();
+                async move {
+                    let requests = vec![
+                        ("n2".to_string(), Payload::Ping),
+                        ("n3".to_string(), Payload::Ping),
+                    ];
+                    network
+                        .gather_rpc(requests, 1, Duration::from_secs(5))
+                        .await
+                }
+            });
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let to_n2 = output
+                .lock()
+                .unwrap()
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice::<Message>(line).unwrap())
+                .find(|message| message.dst == "n2")
+                .unwrap();
+
+            let pong = format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"pong","in_reply_to":{}}}}}"#,
+                to_n2.body.msg_id.unwrap()
+            );
+            assert!(network.route_reply(pong).is_none());
+
+            // n3 never answers, the gather doesn't wait the timeout out for it.
+            let replies = tokio::time::timeout(Duration::from_secs(1), gathering)
+                .await
+                .expect("gather waited for the silent peer")
+                .unwrap();
+            assert_eq!(replies.len(), 1);
+            assert_eq!(replies[0].0, "n2");
+        });
+    }
+}
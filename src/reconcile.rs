@@ -0,0 +1,129 @@
+//! Merging kafka logs that diverged: appends two or more nodes made to the same key on their
+//! own, each handing out offsets past the part of the log they all share.
+//!
+//! The invariant the merge keeps is that an offset, once handed out for a value, never comes
+//! to hold a different one. Divergent appends are therefore never merged into the offsets
+//! they were made at: those stay holes, which polls skip, and the values are renumbered
+//! past every offset any node used. [`Reconciled::renumbered`] tells where each one went.
+//!
+//! The appends are ordered by `(ts, node)`, their Lamport timestamp with the node id as tie
+//! breaker. That order doesn't depend on which node reconciles or in what order it got the
+//! appends, so every node ends up with the same log. Once reconciled, the log is the new
+//! shared part: a later reconciliation only ever appends to it.
+
+use std::collections::BTreeMap;
+
+/// An append a node made to a key on its own, past the part of the log every node shares.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DivergentAppend {
+    /// The Lamport timestamp the append was made at.
+    pub ts: u64,
+    pub node: String,
+    /// The offset the node handed out for it.
+    pub offset: usize,
+    pub value: usize,
+}
+
+/// A log after [`reconcile`], and where each divergent append ended up.
+#[derive(Debug, PartialEq)]
+pub struct Reconciled {
+    pub log: BTreeMap<usize, usize>,
+    /// The new offset of every divergent append, by the node and offset it was made at.
+    pub renumbered: BTreeMap<(String, usize), usize>,
+}
+
+/// Merges the appends nodes made to the same key independently on top of the `shared` log.
+/// An append seen more than once, as when every node passes on all the appends it knows of,
+/// is merged once.
+pub fn reconcile(
+    shared: &BTreeMap<usize, usize>,
+    appends: impl IntoIterator<Item = DivergentAppend>,
+) -> Reconciled {
+    let mut appends: Vec<DivergentAppend> = appends.into_iter().collect();
+    appends.sort_by(|a, b| (a.ts, &a.node, a.offset).cmp(&(b.ts, &b.node, b.offset)));
+    appends.dedup();
+
+    // Past every offset handed out, whether shared or divergent.
+    let start = appends
+        .iter()
+        .map(|append| append.offset + 1)
+        .chain(shared.keys().next_back().map(|last| last + 1))
+        .max()
+        .unwrap_or(0);
+
+    let mut log = shared.clone();
+    let mut renumbered = BTreeMap::new();
+
+    for (offset, append) in (start..).zip(appends) {
+        log.insert(offset, append.value);
+        renumbered.insert((append.node, append.offset), offset);
+    }
+
+    Reconciled { log, renumbered }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(ts: u64, node: &str, offset: usize, value: usize) -> DivergentAppend {
+        DivergentAppend {
+            ts,
+            node: node.to_string(),
+            offset,
+            value,
+        }
+    }
+
+    #[test]
+    fn divergent_logs_reconcile_identically_on_both_nodes() {
+        let shared = BTreeMap::from([(0, 10), (1, 11)]);
+
+        // Both nodes handed out offsets 2 and 3, for different values.
+        let on_n1 = vec![append(4, "n1", 2, 20), append(7, "n1", 3, 21)];
+        let on_n2 = vec![append(5, "n2", 2, 30), append(7, "n2", 3, 31)];
+
+        // Each node merges its own appends with the ones it got from the other.
+        let at_n1 = reconcile(&shared, on_n1.iter().chain(&on_n2).cloned());
+        let at_n2 = reconcile(&shared, on_n2.iter().rev().chain(&on_n1).cloned());
+        assert_eq!(at_n1, at_n2);
+
+        // Equal timestamps are ordered by node id.
+        assert_eq!(
+            at_n1.log,
+            BTreeMap::from([(0, 10), (1, 11), (4, 20), (5, 30), (6, 21), (7, 31)])
+        );
+
+        // No offset handed out before holds a different value now.
+        for append in on_n1.iter().chain(&on_n2) {
+            assert_eq!(at_n1.log.get(&append.offset), None);
+
+            let moved = at_n1.renumbered[&(append.node.clone(), append.offset)];
+            assert_eq!(at_n1.log[&moved], append.value);
+        }
+    }
+
+    #[test]
+    fn appends_known_to_both_nodes_are_merged_once() {
+        let shared = BTreeMap::from([(0, 10)]);
+        let appends = [append(3, "n1", 1, 20), append(3, "n2", 1, 30)];
+
+        let reconciled = reconcile(&shared, appends.iter().chain(&appends).cloned());
+
+        assert_eq!(reconciled.log, BTreeMap::from([(0, 10), (2, 20), (3, 30)]));
+    }
+
+    #[test]
+    fn a_later_reconciliation_keeps_earlier_offsets() {
+        let first = reconcile(
+            &BTreeMap::from([(0, 10)]),
+            [append(2, "n1", 1, 20), append(2, "n2", 1, 30)],
+        );
+        let second = reconcile(&first.log, [append(9, "n2", 4, 40)]);
+
+        for (offset, value) in &first.log {
+            assert_eq!(second.log[offset], *value);
+        }
+        assert_eq!(second.renumbered[&("n2".to_string(), 4)], 5);
+    }
+}
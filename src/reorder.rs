@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// How long gossip waits for a missing predecessor by default.
+pub const DEFAULT_REORDER_WINDOW: Duration = Duration::from_secs(1);
+
+/// Holds gossip that arrived ahead of its predecessors. Every sender numbers the gossip it
+/// sends each peer from 0, an item is released once all lower numbers from the same sender
+/// were, so each sender's gossip is applied in the order it was sent.
+///
+/// A gap is waited on for `window` after the first item behind it arrived, then the missing
+/// items are skipped. Items already released or skipped are dropped.
+pub struct ReorderBuffer<T> {
+    /// The number expected next from each sender.
+    next: HashMap<String, u64>,
+    /// Items waiting on a gap, with when they arrived.
+    held: HashMap<String, BTreeMap<u64, (Instant, T)>>,
+    pub window: Duration,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            next: HashMap::new(),
+            held: HashMap::new(),
+            window,
+        }
+    }
+
+    /// Takes the `seq`-th item from `sender`, returning whatever it made ready, in order.
+    pub fn push(&mut self, sender: &str, seq: u64, item: T, now: Instant) -> Vec<T> {
+        let next = self.next.entry(sender.to_string()).or_default();
+        if seq < *next {
+            return Vec::new();
+        }
+
+        let held = self.held.entry(sender.to_string()).or_default();
+        // A retransmission of an item still held keeps its place in the window.
+        held.entry(seq).or_insert((now, item));
+
+        release(held, next)
+    }
+
+    /// Skips the gaps waited on longer than the window, returning the items they held back,
+    /// in order per sender.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut ready = Vec::new();
+
+        for (sender, held) in &mut self.held {
+            let next = self.next.entry(sender.clone()).or_default();
+
+            while let Some((&resume, (arrived, _))) = held.first_key_value() {
+                if now.saturating_duration_since(*arrived) < self.window {
+                    break;
+                }
+
+                tracing::warn!(sender, from = *next, to = resume, "skipping gossip gap");
+                *next = resume;
+                ready.extend(release(held, next));
+            }
+        }
+
+        ready
+    }
+
+    /// Time left at `now` until the oldest gap is given up on, `None` if nothing is held.
+    pub fn next_expiry(&self, now: Instant) -> Option<Duration> {
+        self.held
+            .values()
+            .filter_map(|held| held.values().map(|(arrived, _)| *arrived).min())
+            .map(|arrived| (arrived + self.window).saturating_duration_since(now))
+            .min()
+    }
+
+    /// Items held back, from all senders.
+    pub fn held(&self) -> usize {
+        self.held.values().map(BTreeMap::len).sum()
+    }
+}
+
+/// Takes the items from `next` on that follow each other without a gap out of `held`.
+fn release<T>(held: &mut BTreeMap<u64, (Instant, T)>, next: &mut u64) -> Vec<T> {
+    let mut ready = Vec::new();
+
+    while let Some((_, item)) = held.remove(next) {
+        ready.push(item);
+        *next += 1;
+    }
+
+    ready
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_order_gossip_is_applied_in_order() {
+        let now = Instant::now();
+        let mut buffer = ReorderBuffer::new(DEFAULT_REORDER_WINDOW);
+        let mut applied = Vec::new();
+
+        for seq in [2, 0, 3, 1, 1, 5, 4] {
+            applied.extend(buffer.push("n2", seq, seq, now));
+        }
+
+        assert_eq!(applied, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(buffer.held(), 0);
+
+        // Other senders are numbered on their own.
+        assert_eq!(buffer.push("n3", 0, 10, now), [10]);
+    }
+
+    #[test]
+    fn gap_older_than_the_window_is_skipped() {
+        let start = Instant::now();
+        let window = Duration::from_millis(500);
+        let mut buffer = ReorderBuffer::new(window);
+
+        assert!(buffer.push("n2", 1, 1, start).is_empty());
+        assert!(buffer.push("n2", 3, 3, start + window / 2).is_empty());
+        assert_eq!(buffer.next_expiry(start), Some(window));
+        assert!(buffer.expire(start + window / 2).is_empty());
+
+        // Past 0, then past 2 once the item behind that gap waited long enough too.
+        assert_eq!(buffer.expire(start + window), [1]);
+        assert_eq!(buffer.expire(start + window * 3 / 2), [3]);
+        assert_eq!(buffer.next_expiry(start), None);
+
+        // The missing ones are dropped if they still show up.
+        assert!(buffer.push("n2", 0, 0, start + window * 2).is_empty());
+        assert!(buffer.push("n2", 2, 2, start + window * 2).is_empty());
+        assert_eq!(buffer.push("n2", 4, 4, start + window * 2), [4]);
+    }
+}
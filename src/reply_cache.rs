@@ -0,0 +1,76 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::Payload;
+
+/// The replies sent to recent requests, by source and `msg_id`. A retransmitted request is
+/// answered from here instead of being handled again, so handlers with side effects, like
+/// `send` or `add`, needn't be idempotent themselves. Once full, the least recently used
+/// reply makes room.
+pub struct ReplyCache<P = Payload> {
+    capacity: usize,
+    /// Each reply and when it was last used.
+    replies: HashMap<(String, u64), (P, u64)>,
+    /// The keys of `replies` by last use, oldest first.
+    by_use: BTreeMap<u64, (String, u64)>,
+    uses: u64,
+}
+
+impl<P: Clone> ReplyCache<P> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            replies: HashMap::new(),
+            by_use: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    pub fn get(&mut self, src: &str, msg_id: u64) -> Option<P> {
+        let key = (src.to_string(), msg_id);
+        let (payload, last_use) = self.replies.get_mut(&key)?;
+
+        self.uses += 1;
+        self.by_use.remove(last_use);
+        self.by_use.insert(self.uses, key);
+        *last_use = self.uses;
+
+        Some(payload.clone())
+    }
+
+    pub fn insert(&mut self, src: &str, msg_id: u64, payload: P) {
+        let key = (src.to_string(), msg_id);
+
+        self.uses += 1;
+        if let Some((_, last_use)) = self.replies.insert(key.clone(), (payload, self.uses)) {
+            self.by_use.remove(&last_use);
+        }
+        self.by_use.insert(self.uses, key);
+
+        while self.replies.len() > self.capacity {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            self.replies.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_reply_is_evicted() {
+        let mut cache = ReplyCache::new(2);
+
+        cache.insert("c1", 1, Payload::AddOk);
+        cache.insert("c1", 2, Payload::AddOk);
+        assert!(cache.get("c1", 1).is_some());
+
+        cache.insert("c2", 1, Payload::AddOk);
+
+        assert!(cache.get("c1", 1).is_some());
+        assert!(cache.get("c1", 2).is_none());
+        assert!(cache.get("c2", 1).is_some());
+    }
+}
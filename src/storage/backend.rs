@@ -0,0 +1,269 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{read_frame, write_frame, Value, WireFormat};
+
+/// The log of one key. Offsets are absolute: compacting drops values from the front and
+/// moves `base_offset` past them, so later offsets keep their meaning.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Log {
+    pub base_offset: usize,
+    pub values: Vec<Value>,
+}
+
+impl Log {
+    /// The offset the next appended value gets.
+    fn end_offset(&self) -> usize {
+        self.base_offset + self.values.len()
+    }
+
+    /// Up to `limit` values from `offset` on, or from the base offset if that is later,
+    /// together with the offset of the first one.
+    fn slice(&self, offset: usize, limit: usize) -> (usize, Vec<Value>) {
+        let start = offset.max(self.base_offset);
+        let values = self
+            .values
+            .get(start - self.base_offset..)
+            .map(|tail| tail[..tail.len().min(limit)].to_vec())
+            .unwrap_or_default();
+
+        (start, values)
+    }
+
+    /// Drops every value below `before`, returning the new base offset.
+    fn compact(&mut self, before: usize) -> usize {
+        let dropped = before
+            .saturating_sub(self.base_offset)
+            .min(self.values.len());
+
+        self.values.drain(..dropped);
+        self.base_offset += dropped;
+
+        self.base_offset
+    }
+}
+
+/// Where a [`Storage`](super::Storage) keeps its logs. Every key maps to an append-only log
+/// of values, addressed by offset.
+pub trait Backend: Send + Sync + 'static {
+    /// Appends `msg` to the log of `key`, returning its offset.
+    fn store(&self, key: String, msg: Value) -> usize;
+
+    /// Up to `limit` values of `key` from `offset` on, and the offset of the first one. That
+    /// is the base offset if `offset` was compacted away. Empty past the end of the log or
+    /// for a missing key.
+    fn get_range(&self, key: &str, offset: usize, limit: usize) -> (usize, Vec<Value>);
+
+    /// Removes `key` and its whole log, returning whether it existed.
+    fn delete(&self, key: &str) -> bool;
+
+    /// Appends `to` if the last value of the log is `from`, atomically with respect to
+    /// every other operation on `key`.
+    fn cas(&self, key: String, from: Value, to: Value) -> Result<(), String>;
+
+    /// Drops the values of `key` below offset `before`, returning the new base offset.
+    fn compact(&self, key: &str, before: usize) -> usize;
+
+    /// Every log, for snapshots.
+    fn entries(&self) -> HashMap<String, Log>;
+
+    fn get(&self, key: &str, offset: usize) -> (usize, Vec<Value>) {
+        self.get_range(key, offset, usize::MAX)
+    }
+
+    /// The offset the next value of `key` gets.
+    fn len(&self, key: &str) -> usize {
+        let (start, values) = self.get(key, 0);
+
+        start + values.len()
+    }
+}
+
+/// Keeps every log in memory.
+#[derive(Default)]
+pub struct MemoryBackend {
+    map: DashMap<String, Log>,
+}
+
+impl From<HashMap<String, Log>> for MemoryBackend {
+    fn from(map: HashMap<String, Log>) -> Self {
+        Self {
+            map: map.into_iter().collect(),
+        }
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn store(&self, key: String, msg: Value) -> usize {
+        let mut log = self.map.entry(key).or_default();
+        log.values.push(msg);
+
+        log.end_offset() - 1
+    }
+
+    fn get_range(&self, key: &str, offset: usize, limit: usize) -> (usize, Vec<Value>) {
+        self.map
+            .get(key)
+            .map_or((offset, Vec::new()), |log| log.slice(offset, limit))
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        self.map.remove(key).is_some()
+    }
+
+    /// Holds the key's shard lock across the compare and the append, so concurrent
+    /// connections can't interleave between them.
+    fn cas(&self, key: String, from: Value, to: Value) -> Result<(), String> {
+        match self.map.entry(key) {
+            Entry::Occupied(mut entry) => match entry.get().values.last() {
+                Some(last) if *last == from => {
+                    entry.get_mut().values.push(to);
+
+                    Ok(())
+                }
+                last => Err(format!("expected {from:?}, found {last:?}")),
+            },
+            Entry::Vacant(_) => Err("key does not exist".to_string()),
+        }
+    }
+
+    fn compact(&self, key: &str, before: usize) -> usize {
+        self.map
+            .get_mut(key)
+            .map_or(0, |mut log| log.compact(before))
+    }
+
+    fn entries(&self) -> HashMap<String, Log> {
+        self.map
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    fn len(&self, key: &str) -> usize {
+        self.map.get(key).map_or(0, |log| log.end_offset())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Store { key: String, msg: Value },
+    Delete { key: String },
+    Compact { key: String, before: usize },
+}
+
+/// Appends every change to a log file and replays it on open. Reads are still served from
+/// memory, so this only buys durability, not room.
+pub struct FileBackend {
+    memory: MemoryBackend,
+    /// Held across the change and its record, so the file has them in the applied order.
+    log: Mutex<File>,
+}
+
+impl FileBackend {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let memory = MemoryBackend::default();
+
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(path)?);
+
+            loop {
+                match read_frame(&mut reader, WireFormat::Bincode) {
+                    Ok(Record::Store { key, msg }) => {
+                        memory.store(key, msg);
+                    }
+                    Ok(Record::Delete { key }) => {
+                        memory.delete(&key);
+                    }
+                    Ok(Record::Compact { key, before }) => {
+                        memory.compact(&key, before);
+                    }
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            memory,
+            log: Mutex::new(log),
+        })
+    }
+
+    fn append(log: &mut File, record: &Record) {
+        if let Err(err) = write_frame(log, WireFormat::Bincode, record) {
+            tracing::error!(%err, "storage: appending to the log failed");
+        }
+    }
+}
+
+impl Backend for FileBackend {
+    fn store(&self, key: String, msg: Value) -> usize {
+        let mut log = self.log.lock().unwrap();
+
+        let offset = self.memory.store(key.clone(), msg.clone());
+        Self::append(&mut log, &Record::Store { key, msg });
+
+        offset
+    }
+
+    fn get_range(&self, key: &str, offset: usize, limit: usize) -> (usize, Vec<Value>) {
+        self.memory.get_range(key, offset, limit)
+    }
+
+    fn delete(&self, key: &str) -> bool {
+        let mut log = self.log.lock().unwrap();
+
+        let existed = self.memory.delete(key);
+        if existed {
+            Self::append(
+                &mut log,
+                &Record::Delete {
+                    key: key.to_string(),
+                },
+            );
+        }
+
+        existed
+    }
+
+    fn cas(&self, key: String, from: Value, to: Value) -> Result<(), String> {
+        let mut log = self.log.lock().unwrap();
+
+        self.memory.cas(key.clone(), from, to.clone())?;
+        Self::append(&mut log, &Record::Store { key, msg: to });
+
+        Ok(())
+    }
+
+    fn compact(&self, key: &str, before: usize) -> usize {
+        let mut log = self.log.lock().unwrap();
+
+        let base_offset = self.memory.compact(key, before);
+        Self::append(
+            &mut log,
+            &Record::Compact {
+                key: key.to_string(),
+                before,
+            },
+        );
+
+        base_offset
+    }
+
+    fn entries(&self) -> HashMap<String, Log> {
+        self.memory.entries()
+    }
+
+    fn len(&self, key: &str) -> usize {
+        self.memory.len(key)
+    }
+}
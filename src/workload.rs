@@ -0,0 +1,217 @@
+mod broadcast;
+mod counter;
+mod echo;
+mod generate;
+mod kafka;
+mod kv;
+mod txn;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::{Message, NodeCtx, NodeError, NodePayload, Payload};
+
+pub use broadcast::Broadcast;
+pub use counter::{GCounter, SeqKvCounter};
+pub use echo::Echo;
+pub use generate::{GeneratePayload, UniqueIds};
+pub use kafka::Kafka;
+pub use kv::KeyValue;
+pub use txn::Txn;
+
+/// A single Maelstrom challenge over payloads `P`. The node takes care of `init` and
+/// envelope checks and hands every other message to the workload it was started with.
+pub trait Workload<P = Payload> {
+    /// The name the workload is selected by on the command line, also used in logs.
+    fn name(&self) -> &'static str;
+
+    fn handle(&mut self, message: Message<P>, ctx: &mut NodeCtx<P>) -> Result<P, NodeError>;
+
+    /// Time left at `now` until the workload wants [`Workload::tick`] to run, `None` if it
+    /// has no timers.
+    fn next_tick(&self, _now: Instant) -> Option<Duration> {
+        None
+    }
+
+    /// Called from the main loop after every message and timeout.
+    fn tick(&mut self, _ctx: &mut NodeCtx<P>) {}
+
+    /// Called once when the test is over, to log whatever is still outstanding.
+    fn on_shutdown(&mut self, _ctx: &mut NodeCtx<P>) {}
+}
+
+/// A workload that answers each message on its own, without timers, peers or deferred
+/// replies. Every handler is a [`Workload`] too, so it runs on a node of its payload enum.
+pub trait NodeHandler<P> {
+    fn name(&self) -> &'static str;
+
+    /// The reply to `msg`, `None` if it gets none.
+    fn handle(&mut self, msg: Message<P>) -> Option<P>;
+}
+
+impl<P: NodePayload, H: NodeHandler<P>> Workload<P> for H {
+    fn name(&self) -> &'static str {
+        NodeHandler::name(self)
+    }
+
+    fn handle(&mut self, message: Message<P>, _ctx: &mut NodeCtx<P>) -> Result<P, NodeError> {
+        Ok(NodeHandler::handle(self, message).unwrap_or_else(P::dont_reply))
+    }
+}
+
+/// Fallback for payloads a workload doesn't know: stray replies are dropped, requests are
+/// answered with `not_supported`.
+pub fn unhandled(payload: &Payload) -> Result<Payload, NodeError> {
+    if payload.is_reply() {
+        Ok(Payload::DontReply)
+    } else {
+        Err(NodeError::CurrentlyUnsupported)
+    }
+}
+
+/// How far timer periods stray from their interval by default, as a fraction of it.
+const DEFAULT_JITTER: f64 = 0.2;
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// The splitmix64 generator behind timer jitter and a node's other random choices. Every
+/// node owns one, seeded from its id on init, so a node's timers are reproducible.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self {
+            state: GOLDEN_GAMMA,
+        }
+    }
+}
+
+impl Rng {
+    pub fn seeded(node_id: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        node_id.hash(&mut hasher);
+
+        Self {
+            state: hasher.finish(),
+        }
+    }
+
+    /// The next value of the generator, uniform in `[0, 1)`.
+    pub fn sample(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A random index below `len`.
+    pub fn index(&mut self, len: usize) -> usize {
+        ((self.sample() * len as f64) as usize).min(len.saturating_sub(1))
+    }
+}
+
+/// A periodic timer polled from [`Workload::tick`] with the node clock's time. Every period
+/// is randomly stretched or shrunk by up to `jitter` of the interval, so timers of different
+/// nodes drift apart instead of firing in bursts.
+pub struct Timer {
+    interval: Duration,
+    /// The length of the current period.
+    period: Duration,
+    /// When the current period started, `None` until the timer is first polled.
+    last: Option<Instant>,
+    /// Fraction of the interval a period may deviate by, `0.0` for a fixed interval.
+    pub jitter: f64,
+}
+
+impl Timer {
+    pub fn new(interval: Duration) -> Self {
+        Self::with_jitter(interval, DEFAULT_JITTER)
+    }
+
+    pub fn with_jitter(interval: Duration, jitter: f64) -> Self {
+        Self {
+            interval,
+            period: interval,
+            last: None,
+            jitter,
+        }
+    }
+
+    fn next_period(&self, rng: &mut Rng) -> Duration {
+        if self.jitter <= 0.0 {
+            return self.interval;
+        }
+
+        self.interval
+            .mul_f64(1.0 + self.jitter * (2.0 * rng.sample() - 1.0))
+    }
+
+    pub fn remaining(&self, now: Instant) -> Duration {
+        let elapsed = self.last.map_or(Duration::ZERO, |last| now - last);
+
+        self.period.saturating_sub(elapsed)
+    }
+
+    /// Returns `true` and restarts the timer if the period has elapsed by `now`. The first
+    /// period starts at the first call, with its length drawn from `rng` like every later one.
+    pub fn fire(&mut self, now: Instant, rng: &mut Rng) -> bool {
+        let last = match self.last {
+            Some(last) => last,
+            None => {
+                self.period = self.next_period(rng);
+                *self.last.insert(now)
+            }
+        };
+
+        if now - last < self.period {
+            return false;
+        }
+
+        self.last = Some(now);
+        self.period = self.next_period(rng);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jittered_periods_stay_within_bounds() {
+        let interval = Duration::from_millis(100);
+        let mut rng = Rng::seeded("n1");
+
+        for _ in 0..100 {
+            let period = Timer::new(interval).next_period(&mut rng);
+
+            assert!(period >= interval.mul_f64(1.0 - DEFAULT_JITTER));
+            assert!(period <= interval.mul_f64(1.0 + DEFAULT_JITTER));
+        }
+
+        assert_eq!(
+            Timer::with_jitter(interval, 0.0).next_period(&mut rng),
+            interval
+        );
+    }
+
+    #[test]
+    fn each_node_draws_its_own_jitter() {
+        let draw = |node_id| {
+            let mut rng = Rng::seeded(node_id);
+            (0..8).map(|_| rng.sample()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(draw("n1"), draw("n1"));
+        assert_ne!(draw("n1"), draw("n2"));
+    }
+}
@@ -0,0 +1,590 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::reorder::{ReorderBuffer, DEFAULT_REORDER_WINDOW};
+use crate::workload::{unhandled, Timer, Workload};
+use crate::{id_kind, Body, IdKind, Message, NodeCtx, NodeError, Payload, Provenance, ReadResult};
+
+/// Gossips every newly seen value along a spanning tree of the topology in per-neighbor
+/// batches. While a tree neighbor is suspected, the other topology neighbors get the values
+/// as well to route around it.
+///
+/// Each batch is retried until it is acknowledged, or until it used up its attempts and is
+/// dead-lettered. Retries back off exponentially, until any acknowledgement suggests the
+/// partition healed. Batches are numbered per neighbor, and a neighbor holds back a batch
+/// that overtook an earlier one until that one arrives or the reorder window runs out.
+///
+/// In hub mode the topology is ignored: nodes forward client values only to the hub, the
+/// smallest node id, which fans them out to everyone else. While the hub is suspected, nodes
+/// gossip to all peers directly instead.
+pub struct Broadcast {
+    /// Every value seen, with where it came from first.
+    messages: HashMap<usize, Provenance>,
+    hub_mode: bool,
+    topology: HashMap<String, Vec<String>>,
+    /// The [`spanning_tree`] of `topology`.
+    tree: HashMap<String, Vec<String>>,
+    pending: HashMap<u64, Outstanding>,
+    /// How often a batch is sent, the first send included, before it is given up on.
+    pub max_attempts: usize,
+    /// Batches given up on.
+    dead_lettered: usize,
+    /// Values sent that the receiver already had.
+    duplicates: usize,
+    /// The wait before the first retry, doubled with every further one up to `retry_cap`.
+    pub retry_base: Duration,
+    pub retry_cap: Duration,
+    outbound: HashMap<String, Vec<usize>>,
+    flush: Timer,
+    /// The number of the next batch to each neighbor.
+    next_seq: HashMap<String, u64>,
+    /// Batches from peers waiting on an earlier one.
+    pub reorder: ReorderBuffer<Message>,
+}
+
+/// With the default backoff, enough to ride out a partition of over a minute.
+const DEFAULT_MAX_ATTEMPTS: usize = 20;
+
+const DEFAULT_RETRY_BASE: Duration = Duration::from_millis(500);
+const DEFAULT_RETRY_CAP: Duration = Duration::from_secs(5);
+
+/// A batch waiting for its `broadcast_ok`.
+struct Outstanding {
+    dst: String,
+    payload: Payload,
+    attempts: usize,
+    /// Sends since the backoff was last reset.
+    backoff: u32,
+    next_retry: Instant,
+}
+
+/// The wait after the `backoff`-th send in a row without an acknowledgement.
+fn retry_delay(base: Duration, cap: Duration, backoff: u32) -> Duration {
+    2u32.checked_pow(backoff.saturating_sub(1))
+        .map_or(cap, |factor| base.saturating_mul(factor))
+        .min(cap)
+}
+
+impl Default for Broadcast {
+    fn default() -> Self {
+        Self {
+            messages: HashMap::new(),
+            hub_mode: false,
+            topology: HashMap::new(),
+            tree: HashMap::new(),
+            pending: HashMap::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            dead_lettered: 0,
+            duplicates: 0,
+            retry_base: DEFAULT_RETRY_BASE,
+            retry_cap: DEFAULT_RETRY_CAP,
+            outbound: HashMap::new(),
+            flush: Timer::new(Duration::from_millis(100)),
+            next_seq: HashMap::new(),
+            reorder: ReorderBuffer::new(DEFAULT_REORDER_WINDOW),
+        }
+    }
+}
+
+impl Broadcast {
+    pub fn with_hub() -> Self {
+        Self {
+            hub_mode: true,
+            ..Self::default()
+        }
+    }
+
+    fn hub<'a>(ctx: &NodeCtx<'a>) -> Option<&'a str> {
+        ctx.all_node_ids.iter().min().map(String::as_str)
+    }
+
+    /// Every node but this one and `except`.
+    fn peers(ctx: &NodeCtx, except: &str) -> Vec<String> {
+        ctx.peers()
+            .into_iter()
+            .filter(|node| *node != except)
+            .cloned()
+            .collect()
+    }
+
+    /// Where a value received from `from` goes next. Only ever nodes, gossip sent to a
+    /// client would never be acknowledged and retried forever.
+    fn targets(&self, from: &str, ctx: &NodeCtx) -> Vec<String> {
+        if !self.hub_mode {
+            let neighbors = |graph: &HashMap<String, Vec<String>>| -> Vec<String> {
+                graph
+                    .get(ctx.node_id)
+                    .into_iter()
+                    .flatten()
+                    .filter(|neighbor| *neighbor != from && id_kind(neighbor) == IdKind::Node)
+                    .cloned()
+                    .collect()
+            };
+
+            let mut targets = neighbors(&self.tree);
+
+            if targets.iter().any(|neighbor| ctx.is_suspect(neighbor)) {
+                for neighbor in neighbors(&self.topology) {
+                    if !targets.contains(&neighbor) {
+                        targets.push(neighbor);
+                    }
+                }
+            }
+
+            return targets;
+        }
+
+        let Some(hub) = Self::hub(ctx) else {
+            return Vec::new();
+        };
+
+        let from_client = id_kind(from) != IdKind::Node;
+
+        if hub == ctx.node_id {
+            Self::peers(ctx, from)
+        } else if !from_client {
+            // Either the hub or a peer gossiping around it already reached everyone.
+            Vec::new()
+        } else if ctx.is_suspect(hub) {
+            Self::peers(ctx, hub)
+        } else {
+            vec![hub.to_string()]
+        }
+    }
+
+    /// Records `value`, returning whether it was new.
+    fn receive(&mut self, value: usize, from: &str, ts: Option<u64>, ctx: &NodeCtx) -> bool {
+        if self.messages.contains_key(&value) {
+            return false;
+        }
+
+        self.messages.insert(
+            value,
+            Provenance {
+                from: from.to_string(),
+                ts: ts.unwrap_or(0),
+            },
+        );
+
+        for target in self.targets(from, ctx) {
+            self.outbound.entry(target).or_default().push(value);
+        }
+
+        true
+    }
+
+    /// Records the values of a gossiped batch.
+    fn apply(&mut self, batch: Message, ctx: &NodeCtx) {
+        if let Payload::BroadcastBatch { messages, .. } = batch.body.payload {
+            for value in messages {
+                self.receive(value, &batch.src, batch.body.ts, ctx);
+            }
+        }
+    }
+
+    /// Clears the batch `msg_id` and its values from every other batch still waiting on
+    /// the same peer, those may overlap once batches were regrouped around a suspected hub.
+    fn acknowledged(&mut self, msg_id: u64, ctx: &NodeCtx) -> Option<Outstanding> {
+        let acked = self.pending.remove(&msg_id)?;
+
+        if let Payload::BroadcastBatch {
+            messages: delivered,
+            ..
+        } = &acked.payload
+        {
+            self.pending.retain(|_, outstanding| {
+                if outstanding.dst != acked.dst {
+                    return true;
+                }
+
+                let Payload::BroadcastBatch { messages, .. } = &mut outstanding.payload else {
+                    return true;
+                };
+                messages.retain(|value| !delivered.contains(value));
+
+                !messages.is_empty()
+            });
+        }
+
+        // The way to some peer works again, likely the others' too.
+        let first_retry = ctx.now() + self.retry_base;
+
+        for outstanding in self.pending.values_mut() {
+            outstanding.backoff = 0;
+            outstanding.next_retry = outstanding.next_retry.min(first_retry);
+        }
+
+        Some(acked)
+    }
+
+    /// Hands everything still waiting for a suspected hub to all other peers directly.
+    fn bypass_hub(&mut self, ctx: &NodeCtx) {
+        let Some(hub) = Self::hub(ctx) else {
+            return;
+        };
+
+        if hub == ctx.node_id || !ctx.is_suspect(hub) {
+            return;
+        }
+
+        let mut stranded = self.outbound.remove(hub).unwrap_or_default();
+
+        self.pending.retain(|_, outstanding| {
+            if outstanding.dst != hub {
+                return true;
+            }
+
+            if let Payload::BroadcastBatch { messages, .. } = &mut outstanding.payload {
+                stranded.append(messages);
+            }
+
+            false
+        });
+
+        if stranded.is_empty() {
+            return;
+        }
+
+        tracing::info!(
+            hub,
+            count = stranded.len(),
+            "hub suspected, gossiping directly"
+        );
+
+        for peer in Self::peers(ctx, hub) {
+            self.outbound.entry(peer).or_default().extend(&stranded);
+        }
+    }
+
+    fn flush_outbound(&mut self, ctx: &mut NodeCtx) {
+        for (neighbor, messages) in std::mem::take(&mut self.outbound) {
+            if messages.is_empty() {
+                continue;
+            }
+
+            // Held back until the neighbor answers a ping again.
+            if ctx.is_suspect(&neighbor) {
+                self.outbound.insert(neighbor, messages);
+                continue;
+            }
+
+            let seq = self.next_seq.entry(neighbor.clone()).or_default();
+            let payload = Payload::BroadcastBatch {
+                messages,
+                seq: Some(*seq),
+            };
+            // Output only fails once Maelstrom is gone, the node is shutting down then.
+            let Ok(msg_id) = ctx.send(&neighbor, payload.clone()) else {
+                return;
+            };
+            *seq += 1;
+
+            self.pending.insert(
+                msg_id,
+                Outstanding {
+                    dst: neighbor,
+                    payload,
+                    attempts: 1,
+                    backoff: 1,
+                    next_retry: ctx.now() + self.retry_base,
+                },
+            );
+        }
+    }
+
+    fn retry_pending(&mut self, ctx: &mut NodeCtx) {
+        let src = ctx.node_id.to_string();
+        let now = ctx.now();
+        let (max_attempts, base, cap) = (self.max_attempts, self.retry_base, self.retry_cap);
+        let mut dead_lettered = 0;
+
+        self.pending.retain(|msg_id, outstanding| {
+            if now < outstanding.next_retry {
+                return true;
+            }
+
+            // Checked on again later, without using up an attempt.
+            if ctx.is_suspect(&outstanding.dst) {
+                outstanding.next_retry = now + base;
+                return true;
+            }
+
+            if outstanding.attempts >= max_attempts {
+                tracing::warn!(
+                    msg_id,
+                    dst = outstanding.dst,
+                    payload = ?outstanding.payload,
+                    attempts = outstanding.attempts,
+                    "dead-lettering unacknowledged broadcast"
+                );
+                dead_lettered += 1;
+
+                return false;
+            }
+
+            // Output only fails once Maelstrom is gone, what's left is logged on shutdown.
+            let _ = ctx.send_to_network(Message {
+                src: src.clone(),
+                dst: outstanding.dst.clone(),
+                body: Body {
+                    msg_id: Some(*msg_id),
+                    in_reply_to: None,
+                    ts: None,
+                    payload: outstanding.payload.clone(),
+                },
+            });
+            outstanding.attempts += 1;
+            outstanding.backoff += 1;
+            outstanding.next_retry = now + retry_delay(base, cap, outstanding.backoff);
+
+            true
+        });
+
+        self.dead_lettered += dead_lettered;
+    }
+}
+
+/// A spanning tree of `topology`, as the tree neighbors, parent and children, of every node.
+/// Built by a BFS from the smallest node id over the topology taken as undirected, visiting
+/// neighbors in order, so every node computes the same tree. Nodes the root can't reach get
+/// a tree of their own, rooted the same way.
+pub fn spanning_tree(topology: &HashMap<String, Vec<String>>) -> HashMap<String, Vec<String>> {
+    let mut edges: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+
+    for (node, neighbors) in topology {
+        edges.entry(node).or_default();
+
+        for neighbor in neighbors.iter().filter(|neighbor| *neighbor != node) {
+            edges.entry(node).or_default().insert(neighbor);
+            edges.entry(neighbor).or_default().insert(node);
+        }
+    }
+
+    let mut tree: HashMap<String, Vec<String>> = HashMap::new();
+    let mut visited = HashSet::new();
+
+    for &root in edges.keys() {
+        if !visited.insert(root) {
+            continue;
+        }
+
+        tree.entry(root.to_string()).or_default();
+        let mut queue = VecDeque::from([root]);
+
+        while let Some(node) = queue.pop_front() {
+            for &neighbor in &edges[node] {
+                if visited.insert(neighbor) {
+                    tree.entry(node.to_string())
+                        .or_default()
+                        .push(neighbor.to_string());
+                    tree.entry(neighbor.to_string())
+                        .or_default()
+                        .push(node.to_string());
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+
+    tree
+}
+
+impl Workload for Broadcast {
+    fn name(&self) -> &'static str {
+        "broadcast"
+    }
+
+    fn handle(&mut self, message: Message, ctx: &mut NodeCtx) -> Result<Payload, NodeError> {
+        match message.body.payload {
+            Payload::Broadcast { message: value } => {
+                self.receive(value, &message.src, message.body.ts, ctx);
+
+                Ok(Payload::BroadcastOk)
+            }
+
+            Payload::BroadcastBatch { ref messages, seq } => {
+                let fresh = messages
+                    .iter()
+                    .filter(|value| !self.messages.contains_key(value))
+                    .count();
+
+                let ready = match seq {
+                    Some(seq) => {
+                        let src = message.src.clone();
+                        self.reorder.push(&src, seq, message, ctx.now())
+                    }
+                    None => vec![message],
+                };
+
+                for batch in ready {
+                    self.apply(batch, ctx);
+                }
+
+                Ok(Payload::BroadcastBatchOk { fresh })
+            }
+
+            Payload::BroadcastBatchOk { fresh } => {
+                let acked = message
+                    .body
+                    .in_reply_to
+                    .and_then(|msg_id| self.acknowledged(msg_id, ctx));
+
+                if let Some(Outstanding {
+                    payload: Payload::BroadcastBatch { messages, .. },
+                    ..
+                }) = acked
+                {
+                    self.duplicates += messages.len().saturating_sub(fresh);
+                }
+
+                Ok(Payload::DontReply)
+            }
+
+            Payload::Read { key: None } => {
+                let mut messages: Vec<usize> = self.messages.keys().copied().collect();
+                messages.sort_unstable();
+
+                Ok(Payload::ReadOk(ReadResult::Messages { messages }))
+            }
+
+            Payload::Topology { topology } => {
+                self.tree = spanning_tree(&topology);
+                self.topology = topology;
+
+                Ok(Payload::TopologyOk)
+            }
+
+            #[cfg(feature = "debug_api")]
+            Payload::DebugProvenance => Ok(Payload::DebugProvenanceOk {
+                provenance: self
+                    .messages
+                    .iter()
+                    .map(|(value, provenance)| (*value, provenance.clone()))
+                    .collect(),
+            }),
+
+            payload => unhandled(&payload),
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &mut NodeCtx) {
+        for (msg_id, outstanding) in &self.pending {
+            tracing::info!(
+                msg_id,
+                dst = outstanding.dst,
+                payload = ?outstanding.payload,
+                "broadcast never acknowledged"
+            );
+        }
+
+        if self.dead_lettered > 0 {
+            tracing::info!(count = self.dead_lettered, "broadcasts dead-lettered");
+        }
+
+        if self.reorder.held() > 0 {
+            tracing::info!(
+                count = self.reorder.held(),
+                "gossip batches still held back"
+            );
+        }
+
+        tracing::info!(count = self.duplicates, "broadcast values delivered twice");
+    }
+
+    fn next_tick(&self, now: Instant) -> Option<Duration> {
+        let next_retry = self
+            .pending
+            .values()
+            .map(|outstanding| outstanding.next_retry.saturating_duration_since(now))
+            .min();
+
+        let next_expiry = self.reorder.next_expiry(now);
+
+        Some(
+            next_retry
+                .unwrap_or(Duration::MAX)
+                .min(next_expiry.unwrap_or(Duration::MAX))
+                .min(self.flush.remaining(now)),
+        )
+    }
+
+    fn tick(&mut self, ctx: &mut NodeCtx) {
+        if self.hub_mode {
+            self.bypass_hub(ctx);
+        }
+
+        for batch in self.reorder.expire(ctx.now()) {
+            self.apply(batch, ctx);
+        }
+
+        self.retry_pending(ctx);
+
+        if self.flush.fire(ctx.now(), ctx.rng) {
+            self.flush_outbound(ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// n1 - n2 - n3
+    ///  |    |    |
+    /// n4 - n5 - n6
+    fn grid() -> HashMap<String, Vec<String>> {
+        [
+            ("n1", vec!["n2", "n4"]),
+            ("n2", vec!["n1", "n3", "n5"]),
+            ("n3", vec!["n2", "n6"]),
+            ("n4", vec!["n1", "n5"]),
+            ("n5", vec!["n2", "n4", "n6"]),
+            ("n6", vec!["n3", "n5"]),
+        ]
+        .into_iter()
+        .map(|(node, neighbors)| {
+            (
+                node.to_string(),
+                neighbors.into_iter().map(String::from).collect(),
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        let base = Duration::from_millis(500);
+        let cap = Duration::from_secs(5);
+
+        let delays: Vec<u128> = (1..=6)
+            .map(|backoff| retry_delay(base, cap, backoff).as_millis())
+            .collect();
+
+        assert_eq!(delays, [500, 1000, 2000, 4000, 5000, 5000]);
+        assert_eq!(retry_delay(base, cap, 0), base);
+        assert_eq!(retry_delay(base, cap, 100), cap);
+    }
+
+    #[test]
+    fn spanning_tree_drops_the_cycles_of_a_mesh() {
+        let tree = spanning_tree(&grid());
+
+        let expected = [
+            ("n1", vec!["n2", "n4"]),
+            ("n2", vec!["n1", "n3", "n5"]),
+            ("n3", vec!["n2", "n6"]),
+            ("n4", vec!["n1"]),
+            ("n5", vec!["n2"]),
+            ("n6", vec!["n3"]),
+        ];
+
+        for (node, neighbors) in expected {
+            assert_eq!(tree[node], neighbors, "tree neighbors of {node}");
+        }
+
+        // One edge fewer than nodes, and the same no matter the map's iteration order.
+        let edges: usize = tree.values().map(Vec::len).sum();
+        assert_eq!(edges / 2, 5);
+        assert_eq!(spanning_tree(&grid()), tree);
+    }
+}
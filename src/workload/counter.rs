@@ -0,0 +1,346 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::reorder::{ReorderBuffer, DEFAULT_REORDER_WINDOW};
+use crate::workload::{unhandled, Timer, Workload};
+use crate::{MaelstromError, Message, NodeCtx, NodeError, Payload, ReadResult};
+
+/// How long a quorum read waits for the peers' counter maps.
+const QUORUM_READ_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// PN-counter: every node only adds to its own entries, positive deltas to its increment and
+/// negative ones to its decrement, and periodically gossips both maps, merging by
+/// element-wise max. The value is the sum of increments minus the sum of decrements. Rounds
+/// with nothing new since the last one are skipped.
+///
+/// Each round only reaches `gossip_fanout` peers that haven't got the current maps yet,
+/// taking turns around the peers from a random starting point. Gossip is numbered per peer,
+/// and a peer merges it in the order it was sent.
+///
+/// With quorum reads, a read first merges the maps of a majority of the nodes into a copy of
+/// its own, so it also sees adds gossip hasn't brought in yet. That makes reads fresher, not
+/// linearizable: an add is acknowledged by the node it went to alone, a read can still miss
+/// it until some node of the majority heard of it. The merged copy only answers the read,
+/// gossip still brings the adds in.
+pub struct GCounter {
+    increments: HashMap<String, u64>,
+    decrements: HashMap<String, u64>,
+    quorum_reads: bool,
+    gossip: Timer,
+    /// Set when the maps changed since they were last gossiped to every peer.
+    dirty: bool,
+    /// Peers that got the maps since they last changed.
+    reached: HashSet<String>,
+    /// Peers gossiped to per round, all of them by default.
+    pub gossip_fanout: usize,
+    /// Where in the peer list the next round starts, random at first.
+    rotation: Option<usize>,
+    /// The number of the next gossip to each peer.
+    next_seq: HashMap<String, u64>,
+    /// Gossip from peers waiting on an earlier one, as its increments and decrements.
+    pub reorder: ReorderBuffer<Maps>,
+}
+
+type Maps = (HashMap<String, u64>, HashMap<String, u64>);
+
+impl Default for GCounter {
+    fn default() -> Self {
+        Self::with_gossip_interval(Duration::from_millis(300))
+    }
+}
+
+/// The counter value the maps add up to.
+fn net(increments: &HashMap<String, u64>, decrements: &HashMap<String, u64>) -> i64 {
+    increments.values().sum::<u64>() as i64 - decrements.values().sum::<u64>() as i64
+}
+
+/// Merges `incoming` into `own` by element-wise max, returning whether anything grew. Only
+/// this node writes its own entry, a peer's copy of it can only be stale.
+fn merge_max(
+    own: &mut HashMap<String, u64>,
+    incoming: HashMap<String, u64>,
+    node_id: &str,
+) -> bool {
+    let mut grew = false;
+
+    for (node, value) in incoming.into_iter().filter(|(node, _)| node != node_id) {
+        let entry = own.entry(node).or_default();
+        if value > *entry {
+            *entry = value;
+            grew = true;
+        }
+    }
+
+    grew
+}
+
+impl GCounter {
+    pub fn with_gossip_interval(interval: Duration) -> Self {
+        Self {
+            increments: HashMap::new(),
+            decrements: HashMap::new(),
+            quorum_reads: false,
+            gossip: Timer::new(interval),
+            dirty: false,
+            reached: HashSet::new(),
+            gossip_fanout: usize::MAX,
+            rotation: None,
+            next_seq: HashMap::new(),
+            reorder: ReorderBuffer::new(DEFAULT_REORDER_WINDOW),
+        }
+    }
+
+    pub fn with_quorum_reads() -> Self {
+        Self {
+            quorum_reads: true,
+            ..Self::default()
+        }
+    }
+
+    fn value(&self) -> i64 {
+        net(&self.increments, &self.decrements)
+    }
+
+    fn merge(
+        &mut self,
+        increments: HashMap<String, u64>,
+        decrements: HashMap<String, u64>,
+        ctx: &NodeCtx,
+    ) {
+        let grew = merge_max(&mut self.increments, increments, ctx.node_id);
+        let shrank = merge_max(&mut self.decrements, decrements, ctx.node_id);
+
+        if grew || shrank {
+            self.changed();
+        }
+    }
+
+    fn changed(&mut self) {
+        self.dirty = true;
+        self.reached.clear();
+    }
+
+    /// Answers a read once the maps of enough peers to make up a majority with this node
+    /// are merged into a copy of its own.
+    fn quorum_read(&self, ctx: &mut NodeCtx) -> Result<Payload, NodeError> {
+        let peers: Vec<(String, Payload)> = ctx
+            .peers()
+            .into_iter()
+            .filter(|node| !ctx.is_suspect(node))
+            .map(|node| (node.clone(), Payload::CounterRead))
+            .collect();
+
+        let needed = ctx.all_node_ids.len() / 2;
+        let node_id = ctx.node_id.to_string();
+        let mut increments = self.increments.clone();
+        let mut decrements = self.decrements.clone();
+        let network = ctx.network.clone();
+
+        ctx.defer(async move {
+            let replies = network.gather_rpc(peers, needed, QUORUM_READ_TIMEOUT).await;
+
+            if replies.len() < needed {
+                tracing::warn!(replies = replies.len(), needed, "quorum read timed out");
+                return Err(NodeError::QuorumUnavailable);
+            }
+
+            for (_, reply) in replies {
+                if let Payload::CounterReadOk {
+                    increments: peer_increments,
+                    decrements: peer_decrements,
+                } = reply
+                {
+                    merge_max(&mut increments, peer_increments, &node_id);
+                    merge_max(&mut decrements, peer_decrements, &node_id);
+                }
+            }
+
+            Ok(Payload::ReadOk(ReadResult::Value {
+                value: net(&increments, &decrements).into(),
+            }))
+        })
+    }
+
+    fn gossip_counters(&mut self, ctx: &mut NodeCtx) {
+        if !self.dirty {
+            return;
+        }
+
+        let peers: Vec<String> = ctx.peers().into_iter().cloned().collect();
+        if peers.is_empty() {
+            self.dirty = false;
+            return;
+        }
+
+        let start = *self
+            .rotation
+            .get_or_insert_with(|| ctx.rng.index(peers.len()));
+
+        // Suspected peers are passed over, staying dirty repeats the round once they're back.
+        let targets: Vec<(usize, String)> = (0..peers.len())
+            .map(|i| (start + i) % peers.len())
+            .filter(|i| !self.reached.contains(&peers[*i]) && !ctx.is_suspect(&peers[*i]))
+            .take(self.gossip_fanout.max(1))
+            .map(|i| (i, peers[i].clone()))
+            .collect();
+
+        if let Some((last, _)) = targets.last() {
+            self.rotation = Some((last + 1) % peers.len());
+        }
+
+        for (_, peer) in targets {
+            let seq = self.next_seq.entry(peer.clone()).or_default();
+            let gossip = Payload::CounterGossip {
+                increments: self.increments.clone(),
+                decrements: self.decrements.clone(),
+                seq: Some(*seq),
+            };
+
+            // Stays dirty, so a round that didn't reach everyone is repeated.
+            if ctx.send(&peer, gossip).is_err() {
+                return;
+            }
+            *seq += 1;
+
+            self.reached.insert(peer);
+        }
+
+        self.dirty = peers.iter().any(|peer| !self.reached.contains(peer));
+    }
+}
+
+impl Workload for GCounter {
+    fn name(&self) -> &'static str {
+        "counter"
+    }
+
+    fn handle(&mut self, message: Message, ctx: &mut NodeCtx) -> Result<Payload, NodeError> {
+        match message.body.payload {
+            Payload::Add { delta } => {
+                let entries = if delta < 0 {
+                    &mut self.decrements
+                } else {
+                    &mut self.increments
+                };
+
+                *entries.entry(ctx.node_id.to_string()).or_default() += delta.unsigned_abs();
+                self.changed();
+
+                Ok(Payload::AddOk)
+            }
+
+            Payload::Read { key: None } if self.quorum_reads => self.quorum_read(ctx),
+
+            Payload::Read { key: None } => Ok(Payload::ReadOk(ReadResult::Value {
+                value: self.value().into(),
+            })),
+
+            Payload::CounterGossip {
+                increments,
+                decrements,
+                seq,
+            } => {
+                let ready = match seq {
+                    Some(seq) => {
+                        let maps = (increments, decrements);
+                        self.reorder.push(&message.src, seq, maps, ctx.now())
+                    }
+                    None => vec![(increments, decrements)],
+                };
+
+                for (increments, decrements) in ready {
+                    self.merge(increments, decrements, ctx);
+                }
+
+                Ok(Payload::DontReply)
+            }
+
+            Payload::CounterRead => Ok(Payload::CounterReadOk {
+                increments: self.increments.clone(),
+                decrements: self.decrements.clone(),
+            }),
+
+            payload => unhandled(&payload),
+        }
+    }
+
+    fn next_tick(&self, now: Instant) -> Option<Duration> {
+        let next_expiry = self.reorder.next_expiry(now).unwrap_or(Duration::MAX);
+
+        Some(self.gossip.remaining(now).min(next_expiry))
+    }
+
+    fn tick(&mut self, ctx: &mut NodeCtx) {
+        for (increments, decrements) in self.reorder.expire(ctx.now()) {
+            self.merge(increments, decrements, ctx);
+        }
+
+        if self.gossip.fire(ctx.now(), ctx.rng) {
+            self.gossip_counters(ctx);
+        }
+    }
+}
+
+/// The seq-kv key the [`SeqKvCounter`] total is kept under.
+const SEQ_KV_COUNTER_KEY: &str = "counter";
+
+/// Counter kept in Maelstrom's `seq-kv` service instead of gossiped between the nodes. An
+/// add reads the total and swaps in the sum with a compare-and-set, starting over when
+/// another node's add came in between.
+///
+/// `seq-kv` is only sequentially consistent, a plain read may be served from a state older
+/// than adds other nodes already had acknowledged. A read therefore first writes a fresh
+/// value to a key of this node's own: the service has to serve the read after that write,
+/// from the latest state.
+#[derive(Default)]
+pub struct SeqKvCounter;
+
+impl Workload for SeqKvCounter {
+    fn name(&self) -> &'static str {
+        "counter-seq-kv"
+    }
+
+    fn handle(&mut self, message: Message, ctx: &mut NodeCtx) -> Result<Payload, NodeError> {
+        match message.body.payload {
+            Payload::Add { delta } => {
+                let kv = ctx.seq_kv();
+
+                ctx.defer(async move {
+                    loop {
+                        let total = match kv.read(SEQ_KV_COUNTER_KEY).await {
+                            Ok(total) => total.as_i64().ok_or(NodeError::IllegalPayloadType)?,
+                            Err(NodeError::Remote(MaelstromError::KeyDoesNotExist)) => 0,
+                            Err(err) => return Err(err),
+                        };
+
+                        match kv.cas(SEQ_KV_COUNTER_KEY, total, total + delta, true).await {
+                            Ok(()) => return Ok(Payload::AddOk),
+                            Err(NodeError::Remote(MaelstromError::PreconditionFailed)) => {}
+                            Err(err) => return Err(err),
+                        }
+                    }
+                })
+            }
+
+            Payload::Read { key: None } => {
+                let kv = ctx.seq_kv();
+                let barrier = format!("read-barrier-{}", ctx.node_id);
+                let fresh = ctx.rng.sample();
+
+                ctx.defer(async move {
+                    kv.write(barrier, fresh).await?;
+
+                    let value = match kv.read(SEQ_KV_COUNTER_KEY).await {
+                        Ok(total) => total,
+                        Err(NodeError::Remote(MaelstromError::KeyDoesNotExist)) => 0.into(),
+                        Err(err) => return Err(err),
+                    };
+
+                    Ok(Payload::ReadOk(ReadResult::Value { value }))
+                })
+            }
+
+            payload => unhandled(&payload),
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use crate::workload::{unhandled, Workload};
+use crate::{Message, NodeCtx, NodeError, Payload};
+
+/// Answers every `echo` with the same string.
+pub struct Echo;
+
+impl Workload for Echo {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn handle(&mut self, message: Message, _ctx: &mut NodeCtx) -> Result<Payload, NodeError> {
+        match message.body.payload {
+            Payload::Echo { echo } => Ok(Payload::EchoOk { echo }),
+
+            payload => unhandled(&payload),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::workload::NodeHandler;
+use crate::{Control, MaelstromError, Message, NodePayload};
+
+/// Milliseconds since the Unix epoch at 2024-01-01, where id timestamps start.
+const EPOCH_MS: u64 = 1_704_067_200_000;
+
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// The messages of the generate workload, which needs none of the shared [`crate::Payload`]
+/// beyond what every node exchanges.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum GeneratePayload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+
+    Ping,
+    Pong,
+
+    Generate,
+    /// A Snowflake-style id, see [`UniqueIds`].
+    GenerateOk {
+        id: u64,
+    },
+
+    DontReply,
+
+    Error {
+        code: MaelstromError,
+        text: String,
+    },
+
+    #[serde(untagged)]
+    Other(serde_json::Value),
+}
+
+impl NodePayload for GeneratePayload {
+    fn into_init(self) -> Result<(String, Vec<String>), Self> {
+        match self {
+            GeneratePayload::Init { node_id, node_ids } => Ok((node_id, node_ids)),
+            payload => Err(payload),
+        }
+    }
+
+    fn init_ok() -> Self {
+        GeneratePayload::InitOk
+    }
+
+    fn ping() -> Self {
+        GeneratePayload::Ping
+    }
+
+    fn pong() -> Self {
+        GeneratePayload::Pong
+    }
+
+    fn dont_reply() -> Self {
+        GeneratePayload::DontReply
+    }
+
+    fn error(code: MaelstromError, text: String) -> Self {
+        GeneratePayload::Error { code, text }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            GeneratePayload::Init { .. } => "init",
+            GeneratePayload::InitOk => "init_ok",
+            GeneratePayload::Ping => "ping",
+            GeneratePayload::Pong => "pong",
+            GeneratePayload::Generate => "generate",
+            GeneratePayload::GenerateOk { .. } => "generate_ok",
+            GeneratePayload::DontReply => "dont_reply",
+            GeneratePayload::Error { .. } => "error",
+            GeneratePayload::Other(..) => "other",
+        }
+    }
+
+    fn is_reply(&self) -> bool {
+        matches!(
+            self,
+            GeneratePayload::InitOk
+                | GeneratePayload::Pong
+                | GeneratePayload::GenerateOk { .. }
+                | GeneratePayload::Error { .. }
+        )
+    }
+
+    fn control(&self) -> Option<Control<'_>> {
+        match self {
+            GeneratePayload::Init { node_id, .. } => Some(Control::Init { node_id }),
+            GeneratePayload::InitOk => Some(Control::InitOk),
+            GeneratePayload::Ping => Some(Control::Ping),
+            GeneratePayload::Pong => Some(Control::Pong),
+            GeneratePayload::DontReply => Some(Control::DontReply),
+            GeneratePayload::Other(body) => Some(Control::Unknown(
+                body.get("type")
+                    .and_then(serde_json::Value::as_str)
+                    .unwrap_or_default(),
+            )),
+            _ => None,
+        }
+    }
+
+    fn error_code(&self) -> Option<&MaelstromError> {
+        match self {
+            GeneratePayload::Error { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+}
+
+/// Generates Snowflake-style ids: milliseconds since [`EPOCH_MS`] in the high bits, the
+/// node's number in the middle 10 and a per-millisecond sequence in the low 12. Ids of one
+/// node always increase, and nodes never collide as their numbers differ.
+#[derive(Default)]
+pub struct UniqueIds {
+    /// The millisecond the last id was generated in, never going backwards.
+    last_ms: u64,
+    sequence: u64,
+}
+
+/// The numeric suffix of a node id like `n3`, truncated to the bits an id has room for.
+fn node_number(node_id: &str) -> u64 {
+    let digits = node_id.trim_start_matches(|c: char| !c.is_ascii_digit());
+
+    digits.parse::<u64>().unwrap_or(0) & ((1 << NODE_BITS) - 1)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+        .saturating_sub(EPOCH_MS)
+}
+
+impl UniqueIds {
+    /// The next id at wall clock `now_ms`. A clock going backwards reuses the last millisecond,
+    /// and once a millisecond's sequence is exhausted the next one is borrowed from the
+    /// future, so ids keep increasing either way.
+    fn next_id(&mut self, node: u64, now_ms: u64) -> u64 {
+        if now_ms > self.last_ms {
+            self.last_ms = now_ms;
+            self.sequence = 0;
+        } else if self.sequence < MAX_SEQUENCE {
+            self.sequence += 1;
+        } else {
+            self.last_ms += 1;
+            self.sequence = 0;
+        }
+
+        (self.last_ms << (NODE_BITS + SEQUENCE_BITS)) | (node << SEQUENCE_BITS) | self.sequence
+    }
+}
+
+impl NodeHandler<GeneratePayload> for UniqueIds {
+    fn name(&self) -> &'static str {
+        "generate"
+    }
+
+    /// The node only hands over messages addressed to it, so `dest` is this node's id.
+    fn handle(&mut self, msg: Message<GeneratePayload>) -> Option<GeneratePayload> {
+        match msg.body.payload {
+            GeneratePayload::Generate => Some(GeneratePayload::GenerateOk {
+                id: self.next_id(node_number(&msg.dst), now_ms()),
+            }),
+
+            // Stray replies; everything else the node answered itself.
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn burst_within_one_millisecond_is_distinct() {
+        let mut ids = UniqueIds::default();
+
+        let burst: Vec<u64> = (0..2 * (MAX_SEQUENCE + 1))
+            .map(|_| ids.next_id(node_number("n3"), 1_000))
+            .collect();
+
+        assert_eq!(burst.iter().collect::<HashSet<_>>().len(), burst.len());
+        assert!(burst.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // A clock going backwards still yields increasing ids.
+        let after = ids.next_id(node_number("n3"), 500);
+        assert!(after > *burst.last().unwrap());
+    }
+}
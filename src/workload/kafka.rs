@@ -0,0 +1,1263 @@
+use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "debug_api")]
+use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::network::Network;
+use crate::storage::AsyncStorageClient;
+use crate::workload::{unhandled, Workload};
+use crate::{LogEntry, Message, NodeCtx, NodeError, Payload};
+
+/// Kafka-style replicated log, with the logs themselves kept by the storage service.
+/// Every key is owned by one node, the others forward requests for it to the owner. The
+/// owner copies each append to the next nodes in the ring before acknowledging it.
+///
+/// With a retention limit, only the last entries of every log are kept. Offsets stay
+/// absolute, a poll from below the oldest kept entry starts at it and reports the truncation.
+///
+/// Committing an offset for a key without a log skips that key and reports it, or with
+/// strict commits fails the whole batch with `key-does-not-exist`. A batch spanning several
+/// owners is checked with all of them first and only committed once every one accepted its
+/// share, so a refusal leaves every offset as it was.
+///
+/// A replica serves polls of keys it keeps a copy of itself. With a staleness bound it only
+/// does so while its copy is at most that many entries behind the owner's log, as far as it
+/// knows from the appends it has seen, and forwards the poll to the owner otherwise.
+///
+/// Storage requests are made from the handler, so they reach the service in the order the
+/// requests arrived, and their replies are awaited in deferred replies.
+pub struct Kafka {
+    /// Shared with deferred replies making further requests once earlier ones returned.
+    storage: Arc<AsyncStorageClient>,
+    /// Shared with forwarded commits, which apply this node's share once the owners of the
+    /// rest replied.
+    commit_offsets: Arc<Mutex<HashMap<String, usize>>>,
+    strict_commits: bool,
+    /// How many entries of each log to keep, all of them if unset.
+    retention: Option<usize>,
+    /// Shared with polls, which serve them once the other replicas helped fill them in.
+    replicas: Arc<Mutex<Replicas>>,
+    /// Offsets assigned to sends carrying a dedup id, by key and that id. `None` while the
+    /// first send with the id is still being stored, later ones wait for it.
+    deduplicated: Arc<Mutex<HashMap<(String, String), AssignedOffset>>>,
+    /// Keys this node has appended to as their owner, the storage service can't list them.
+    #[cfg(feature = "debug_api")]
+    owned: HashSet<String>,
+}
+
+/// The offset a deduplicated send was stored at, once it was.
+type AssignedOffset = watch::Receiver<Option<usize>>;
+
+/// Entries fetched from storage per request while serving a poll.
+const POLL_CHUNK: usize = 64;
+
+/// The most entries a poll without `max_msgs` returns per key, clients poll again for more.
+const DEFAULT_POLL_LIMIT: usize = 1024;
+
+/// How long a replica serving a poll waits on the other replicas for entries it lacks.
+const READ_REPAIR_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The nodes keeping `key`: its owner followed by the next `replication_factor - 1` nodes in
+/// the ring. Depends only on the set of node ids, not their order, so every node agrees on it.
+fn replica_set<'a>(key: &str, node_ids: &'a [String], replication_factor: usize) -> Vec<&'a str> {
+    let mut ring: Vec<&String> = node_ids.iter().collect();
+    ring.sort();
+
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let owner = (hasher.finish() % ring.len() as u64) as usize;
+
+    (0..replication_factor.clamp(1, ring.len()))
+        .map(|i| ring[(owner + i) % ring.len()].as_str())
+        .collect()
+}
+
+/// Picks the node owning `key`.
+fn owner<'a>(key: &str, node_ids: &'a [String]) -> &'a str {
+    replica_set(key, node_ids, 1)[0]
+}
+
+/// The nodes of the cluster as a poll served in the background needs them.
+struct Cluster {
+    node_id: String,
+    node_ids: Vec<String>,
+    replication_factor: usize,
+}
+
+impl Cluster {
+    fn of(ctx: &NodeCtx) -> Self {
+        Self {
+            node_id: ctx.node_id.to_string(),
+            node_ids: ctx.all_node_ids.to_vec(),
+            replication_factor: ctx.replication_factor,
+        }
+    }
+
+    /// [`replica_set`] of `key` without this node.
+    fn other_replicas(&self, key: &str) -> Vec<String> {
+        replica_set(key, &self.node_ids, self.replication_factor)
+            .into_iter()
+            .filter(|node| *node != self.node_id)
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// The copies a node keeps of logs other nodes own.
+#[derive(Default)]
+struct Replicas {
+    /// Logs by key and offset.
+    logs: HashMap<String, BTreeMap<usize, usize>>,
+    /// The oldest offset kept in each copy, the ones below were compacted away.
+    bases: HashMap<String, usize>,
+    /// The last offset each owner is known to have appended, by key of the copies.
+    leader_offsets: HashMap<String, usize>,
+    /// How far behind its owner a copy may be and still serve polls, unbounded if unset.
+    max_staleness: Option<usize>,
+}
+
+impl Replicas {
+    fn base(&self, key: &str) -> usize {
+        self.bases.get(key).copied().unwrap_or(0)
+    }
+
+    /// Drops the entries below `before` from the copy of `key`.
+    fn compact(&mut self, key: &str, before: usize) {
+        if before <= self.base(key) {
+            return;
+        }
+
+        if let Some(log) = self.logs.get_mut(key) {
+            *log = log.split_off(&before);
+        }
+        self.bases.insert(key.to_string(), before);
+    }
+
+    /// Copies an entry into the local copy of `key`, unless it was already compacted away.
+    fn copy_entry(&mut self, key: &str, offset: usize, value: usize) {
+        let leader_offset = self.leader_offsets.entry(key.to_string()).or_default();
+        *leader_offset = (*leader_offset).max(offset);
+
+        if offset < self.base(key) {
+            return;
+        }
+
+        self.logs
+            .entry(key.to_string())
+            .or_default()
+            .entry(offset)
+            .or_insert(value);
+    }
+
+    /// Offsets below the highest one copied for `key` that haven't arrived yet. Replica
+    /// appends can arrive out of order, leaving holes until the missing ones do.
+    fn missing_offsets(&self, key: &str) -> Vec<usize> {
+        let Some(log) = self.logs.get(key) else {
+            return Vec::new();
+        };
+        let Some(last) = log.keys().next_back() else {
+            return Vec::new();
+        };
+
+        (self.base(key)..*last)
+            .filter(|offset| !log.contains_key(offset))
+            .collect()
+    }
+
+    /// The offset of the first entry missing from the copy of `key`, everything below it has
+    /// been replicated.
+    fn replicated_offset(&self, key: &str) -> usize {
+        let base = self.base(key);
+        let Some(log) = self.logs.get(key) else {
+            return base;
+        };
+
+        (base..).find(|offset| !log.contains_key(offset)).unwrap()
+    }
+
+    /// How many of the entries its owner is known to have appended the copy of `key` lacks,
+    /// counting from the first hole.
+    fn replication_lag(&self, key: &str) -> usize {
+        self.leader_offsets.get(key).map_or(0, |leader_offset| {
+            (leader_offset + 1).saturating_sub(self.replicated_offset(key))
+        })
+    }
+
+    /// Whether the copy of `key` is fresh enough to serve polls from.
+    fn within_staleness(&self, key: &str) -> bool {
+        self.max_staleness
+            .is_none_or(|limit| self.replication_lag(key) <= limit)
+    }
+
+    /// Serves a poll of `key` from the local copy, skipping past any holes in it. Also
+    /// returns the copy's base offset if `offset` lies below it.
+    fn poll(
+        &self,
+        key: &str,
+        offset: usize,
+        max_msgs: usize,
+    ) -> Option<(Vec<LogEntry>, Option<usize>)> {
+        let log = self.logs.get(key)?;
+        let base = self.base(key);
+
+        let entries = log
+            .range(offset.max(base)..)
+            .take(max_msgs)
+            .map(|(offset, value)| LogEntry {
+                offset: *offset,
+                value: *value,
+            })
+            .collect();
+
+        Some((entries, (offset < base).then_some(base)))
+    }
+
+    /// The requests asking the other replicas of the polled keys this node keeps a copy of
+    /// for the entries from the polled offsets on, see [`Replicas::repair`].
+    fn repair_requests(
+        &self,
+        offsets: &BTreeMap<String, usize>,
+        max_msgs: Option<usize>,
+        cluster: &Cluster,
+    ) -> Vec<(String, Payload)> {
+        let mut requests: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+
+        for (key, offset) in offsets {
+            if !self.logs.contains_key(key) {
+                continue;
+            }
+
+            for peer in cluster.other_replicas(key) {
+                requests
+                    .entry(peer)
+                    .or_default()
+                    .insert(key.clone(), *offset);
+            }
+        }
+
+        requests
+            .into_iter()
+            .map(|(peer, offsets)| (peer, Payload::ReplicaPoll { offsets, max_msgs }))
+            .collect()
+    }
+
+    /// Fills what the other replicas answered to [`Replicas::repair_requests`] into the
+    /// copies.
+    fn repair(&mut self, replies: Vec<(String, Payload)>) {
+        for (_, reply) in replies {
+            let Payload::PollOk {
+                messages,
+                truncated,
+            } = reply
+            else {
+                continue;
+            };
+
+            for (key, base) in truncated {
+                self.compact(&key, base);
+            }
+
+            for (key, entries) in messages {
+                for entry in entries {
+                    self.copy_entry(&key, entry.offset, entry.value);
+                }
+            }
+        }
+    }
+
+    /// Serves a poll of keys other nodes own from the copies of them, and returns the polls
+    /// to forward for the rest: keys without a copy go to their replicas, keys whose copy is
+    /// too stale to their owner.
+    fn serve(
+        &self,
+        offsets: BTreeMap<String, usize>,
+        max_msgs: Option<usize>,
+        cluster: &Cluster,
+        messages: &mut BTreeMap<String, Vec<LogEntry>>,
+        truncated: &mut BTreeMap<String, usize>,
+    ) -> Vec<(Vec<String>, Payload)> {
+        let limit = max_msgs.unwrap_or(DEFAULT_POLL_LIMIT);
+        let mut forwards = Vec::new();
+
+        for (owner, offsets) in by_owner(offsets, &cluster.node_ids) {
+            let mut forwarded = BTreeMap::new();
+            let mut stale = BTreeMap::new();
+            for (key, offset) in offsets {
+                if self.logs.contains_key(&key) && !self.within_staleness(&key) {
+                    tracing::debug!(
+                        key,
+                        lag = self.replication_lag(&key),
+                        "copy too stale, polling the owner"
+                    );
+                    stale.insert(key, offset);
+                    continue;
+                }
+
+                match self.poll(&key, offset, limit) {
+                    Some((polled, base)) => {
+                        let missing = self.missing_offsets(&key);
+                        if !missing.is_empty() {
+                            tracing::warn!(key, ?missing, "replica log has holes");
+                        }
+
+                        if let Some(base) = base {
+                            truncated.insert(key.clone(), base);
+                        }
+                        messages.insert(key, polled);
+                    }
+                    None => {
+                        forwarded.insert(key, offset);
+                    }
+                }
+            }
+
+            // Keys with the same owner share their replicas, any of them can serve the poll
+            // if the owner doesn't. Only the owner is sure to be fresh.
+            let replicas = forwarded
+                .keys()
+                .next()
+                .map(|key| cluster.other_replicas(key))
+                .unwrap_or_default();
+
+            for (targets, offsets) in [(replicas, forwarded), (vec![owner.to_string()], stale)] {
+                if !offsets.is_empty() {
+                    forwards.push((targets, Payload::Poll { offsets, max_msgs }));
+                }
+            }
+        }
+
+        forwards
+    }
+}
+
+/// Answers a poll with the entries found so far once the nodes the `forwards` went to
+/// returned theirs.
+async fn forward_polls(
+    network: &Network,
+    mut messages: BTreeMap<String, Vec<LogEntry>>,
+    mut truncated: BTreeMap<String, usize>,
+    forwards: Vec<(Vec<String>, Payload)>,
+) -> Result<Payload, NodeError> {
+    for (targets, request) in forwards {
+        if let Payload::PollOk {
+            messages: polled,
+            truncated: polled_truncated,
+        } = network.forward_rpc(&targets, request).await?
+        {
+            messages.extend(polled);
+            truncated.extend(polled_truncated);
+        }
+    }
+
+    Ok(Payload::PollOk {
+        messages,
+        truncated,
+    })
+}
+
+/// Groups `entries` by the owner of their key.
+fn by_owner<V>(
+    entries: impl IntoIterator<Item = (String, V)>,
+    node_ids: &[String],
+) -> BTreeMap<&str, BTreeMap<String, V>> {
+    let mut grouped: BTreeMap<&str, BTreeMap<String, V>> = BTreeMap::new();
+
+    for (key, value) in entries {
+        grouped
+            .entry(owner(&key, node_ids))
+            .or_default()
+            .insert(key, value);
+    }
+
+    grouped
+}
+
+/// Polls the logs this node owns, fetching at most [`POLL_CHUNK`] entries per storage
+/// request and stopping at the poll's limit, so only what is returned is ever read. Keys
+/// polled from below their compacted region are added to `truncated` with the offset they
+/// were served from.
+async fn poll_owned(
+    storage: &AsyncStorageClient,
+    offsets: &BTreeMap<String, usize>,
+    max_msgs: Option<usize>,
+    messages: &mut BTreeMap<String, Vec<LogEntry>>,
+    truncated: &mut BTreeMap<String, usize>,
+) -> Result<(), NodeError> {
+    let limit = max_msgs.unwrap_or(DEFAULT_POLL_LIMIT);
+
+    for (key, offset) in offsets {
+        let mut vals: Vec<LogEntry> = Vec::new();
+        let mut next = *offset;
+
+        while vals.len() < limit {
+            let chunk = (limit - vals.len()).min(POLL_CHUNK);
+            let Ok((start, v)) = storage.get_range::<usize>(key, next, chunk).await else {
+                return Err(NodeError::StorageUnavailable);
+            };
+
+            if vals.is_empty() && start > *offset {
+                truncated.insert(key.clone(), start);
+            }
+
+            let fetched = v.len();
+            vals.extend(v.into_iter().enumerate().map(|(i, value)| LogEntry {
+                offset: start + i,
+                value,
+            }));
+
+            if fetched < chunk {
+                break;
+            }
+            next = start + fetched;
+        }
+
+        messages.insert(key.clone(), vals);
+    }
+
+    Ok(())
+}
+
+/// The offset below which entries of a log ending at `last` are dropped with `retention`,
+/// if any are.
+fn compaction_point(retention: Option<usize>, last: usize) -> Option<usize> {
+    let limit = retention?;
+
+    (last + 1).checked_sub(limit).filter(|before| *before > 0)
+}
+
+impl Kafka {
+    pub fn new(storage: AsyncStorageClient) -> Self {
+        Self {
+            storage: Arc::new(storage),
+            commit_offsets: Arc::default(),
+            strict_commits: false,
+            retention: None,
+            replicas: Arc::default(),
+            deduplicated: Arc::default(),
+            #[cfg(feature = "debug_api")]
+            owned: HashSet::new(),
+        }
+    }
+
+    /// Keeps only the last `limit` entries of every log.
+    pub fn with_retention(mut self, limit: usize) -> Self {
+        self.retention = Some(limit.max(1));
+        self
+    }
+
+    /// Fails commit batches naming a key without a log instead of skipping the key.
+    pub fn with_strict_commits(mut self) -> Self {
+        self.strict_commits = true;
+        self
+    }
+
+    /// Serves polls from copies at most `limit` entries behind their owner, forwarding the
+    /// rest to the owner.
+    pub fn with_max_staleness(self, limit: usize) -> Self {
+        self.replicas.lock().unwrap().max_staleness = Some(limit);
+        self
+    }
+
+    /// Checks that committing `offsets` of keys this node owns would be accepted, resolving
+    /// to the keys the commit would skip.
+    fn check_commits(
+        &self,
+        offsets: &BTreeMap<String, usize>,
+    ) -> impl Future<Output = Result<Vec<String>, NodeError>> + Send + 'static {
+        let strict_commits = self.strict_commits;
+        let lens: Vec<_> = offsets
+            .iter()
+            .map(|(key, offset)| (key.clone(), *offset, self.storage.len(key)))
+            .collect();
+
+        async move {
+            let mut skipped = Vec::new();
+
+            for (key, offset, len) in lens {
+                let Ok(len) = len.await else {
+                    return Err(NodeError::StorageUnavailable);
+                };
+
+                if len == 0 {
+                    if strict_commits {
+                        return Err(NodeError::KeyDoesNotExist);
+                    }
+
+                    skipped.push(key);
+                } else if offset > len {
+                    return Err(NodeError::PreconditionFailed);
+                }
+            }
+
+            Ok(skipped)
+        }
+    }
+}
+
+impl Workload for Kafka {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn handle(&mut self, message: Message, ctx: &mut NodeCtx) -> Result<Payload, NodeError> {
+        match message.body.payload {
+            Payload::Send { key, msg, dedup_id } => {
+                let owner = owner(&key, ctx.all_node_ids);
+                if owner != ctx.node_id {
+                    return ctx.forward(&[owner], Payload::Send { key, msg, dedup_id });
+                }
+
+                let dedup_key = dedup_id.map(|id| (key.clone(), id));
+                let mut assigned = None;
+
+                if let Some(dedup_key) = &dedup_key {
+                    let mut deduplicated = self.deduplicated.lock().unwrap();
+
+                    if let Some(offset) = deduplicated.get(dedup_key) {
+                        let mut offset = offset.clone();
+
+                        return ctx.defer(async move {
+                            let Ok(offset) = offset.wait_for(Option::is_some).await else {
+                                return Err(NodeError::StorageConnectionError);
+                            };
+
+                            Ok(Payload::SendOk {
+                                offset: offset.unwrap(),
+                            })
+                        });
+                    }
+
+                    let (sender, offset) = watch::channel(None);
+                    deduplicated.insert(dedup_key.clone(), offset);
+                    assigned = Some(sender);
+                }
+
+                let stored = self.storage.store(&key, &msg);
+
+                #[cfg(feature = "debug_api")]
+                self.owned.insert(key.clone());
+
+                // The owner's own copy counts towards the majority.
+                let replicas = replica_set(&key, ctx.all_node_ids, ctx.replication_factor);
+                let quorum = replicas.len() / 2;
+
+                let replicas: Vec<String> = replicas[1..].iter().map(|r| r.to_string()).collect();
+                let storage = self.storage.clone();
+                let deduplicated = self.deduplicated.clone();
+                let retention = self.retention;
+                let network = ctx.network.clone();
+                ctx.defer(async move {
+                    let Ok(offset) = stored.await else {
+                        // Sends waiting on this one fail with it, a retry stores anew.
+                        if let Some(dedup_key) = dedup_key {
+                            deduplicated.lock().unwrap().remove(&dedup_key);
+                        }
+
+                        return Err(NodeError::StorageConnectionError);
+                    };
+
+                    if let Some(assigned) = assigned {
+                        assigned.send_replace(Some(offset));
+                    }
+
+                    if let Some(before) = compaction_point(retention, offset) {
+                        if storage.compact(&key, before).await.is_err() {
+                            return Err(NodeError::StorageConnectionError);
+                        }
+                    }
+
+                    if quorum == 0 {
+                        return Ok(Payload::SendOk { offset });
+                    }
+
+                    network
+                        .quorum_rpc(
+                            &replicas,
+                            Payload::ReplicaAppend { key, msg, offset },
+                            quorum,
+                        )
+                        .await?;
+
+                    Ok(Payload::SendOk { offset })
+                })
+            }
+
+            Payload::Poll { offsets, max_msgs } => {
+                let (owned, copied): (BTreeMap<_, _>, BTreeMap<_, _>) = offsets
+                    .into_iter()
+                    .partition(|(key, _)| owner(key, ctx.all_node_ids) == ctx.node_id);
+
+                // Keys owned elsewhere are served from the local copies, after asking the
+                // other replicas for the entries those lack. Replicas that don't answer in
+                // time are skipped, the poll then sees what this node has.
+                let cluster = Cluster::of(ctx);
+                let repairs = self
+                    .replicas
+                    .lock()
+                    .unwrap()
+                    .repair_requests(&copied, max_msgs, &cluster);
+                let replicas = self.replicas.clone();
+                let storage = self.storage.clone();
+                let network = ctx.network.clone();
+
+                ctx.defer(async move {
+                    let mut messages = BTreeMap::new();
+                    let mut truncated = BTreeMap::new();
+                    poll_owned(&storage, &owned, max_msgs, &mut messages, &mut truncated).await?;
+
+                    if copied.is_empty() {
+                        return Ok(Payload::PollOk {
+                            messages,
+                            truncated,
+                        });
+                    }
+
+                    let wanted = repairs.len();
+                    let repaired = network
+                        .gather_rpc(repairs, wanted, READ_REPAIR_TIMEOUT)
+                        .await;
+
+                    let forwards = {
+                        let mut replicas = replicas.lock().unwrap();
+                        replicas.repair(repaired);
+                        replicas.serve(copied, max_msgs, &cluster, &mut messages, &mut truncated)
+                    };
+
+                    forward_polls(&network, messages, truncated, forwards).await
+                })
+            }
+
+            Payload::PollCommitted { offsets } => {
+                let mut polls = Vec::new();
+                let mut forwards = Vec::new();
+
+                for (owner, offsets) in by_owner(offsets, ctx.all_node_ids) {
+                    if owner != ctx.node_id {
+                        forwards
+                            .push((vec![owner.to_string()], Payload::PollCommitted { offsets }));
+
+                        continue;
+                    }
+
+                    for (key, offset) in offsets {
+                        let committed = self.commit_offsets.lock().unwrap().get(&key).copied();
+                        let Some(committed) = committed else {
+                            continue;
+                        };
+
+                        polls.push((key, offset, committed));
+                    }
+                }
+
+                let storage = self.storage.clone();
+                let network = ctx.network.clone();
+                ctx.defer(async move {
+                    let mut messages = BTreeMap::new();
+                    let mut truncated = BTreeMap::new();
+
+                    for (key, offset, committed) in polls {
+                        let limit = (committed + 1).saturating_sub(offset);
+                        poll_owned(
+                            &storage,
+                            &BTreeMap::from([(key.clone(), offset)]),
+                            Some(limit),
+                            &mut messages,
+                            &mut truncated,
+                        )
+                        .await?;
+
+                        // A poll from below the compacted region starts further on.
+                        if let Some(entries) = messages.get_mut(&key) {
+                            entries.retain(|entry| entry.offset <= committed);
+                        }
+                    }
+
+                    forward_polls(&network, messages, truncated, forwards).await
+                })
+            }
+
+            Payload::CommitOffsets { offsets } => {
+                let mut grouped = by_owner(offsets, ctx.all_node_ids);
+                let mut local = grouped.remove(ctx.node_id).unwrap_or_default();
+                let checked = self.check_commits(&local);
+
+                let forwards: Vec<(String, BTreeMap<String, usize>)> = grouped
+                    .into_iter()
+                    .map(|(owner, offsets)| (owner.to_string(), offsets))
+                    .collect();
+                let network = ctx.network.clone();
+                let commit_offsets = self.commit_offsets.clone();
+
+                // Every owner checks its share before any is committed. Logs only grow, so a
+                // share that passed is still accepted by the time it is committed. Only an
+                // owner becoming unreachable in between leaves the batch partly applied, the
+                // client then gets the error and may retry, committing again is harmless.
+                ctx.defer(async move {
+                    let mut skipped = checked.await?;
+
+                    for (owner, offsets) in &forwards {
+                        if let Payload::CommitOffsetsOk { skipped: forwarded } = network
+                            .forward_rpc(
+                                std::slice::from_ref(owner),
+                                Payload::CheckCommitOffsets {
+                                    offsets: offsets.clone(),
+                                },
+                            )
+                            .await?
+                        {
+                            skipped.extend(forwarded);
+                        }
+                    }
+
+                    for (owner, offsets) in forwards {
+                        network
+                            .forward_rpc(&[owner], Payload::CommitOffsets { offsets })
+                            .await?;
+                    }
+
+                    local.retain(|key, _| !skipped.contains(key));
+                    commit_offsets.lock().unwrap().extend(local);
+
+                    Ok(Payload::CommitOffsetsOk { skipped })
+                })
+            }
+
+            Payload::CheckCommitOffsets { offsets } => {
+                let checked = self.check_commits(&offsets);
+
+                ctx.defer(async move {
+                    Ok(Payload::CommitOffsetsOk {
+                        skipped: checked.await?,
+                    })
+                })
+            }
+
+            Payload::ListCommittedOffsets { keys } => {
+                let mut offsets = BTreeMap::new();
+                let mut forwards = Vec::new();
+                let commit_offsets = self.commit_offsets.lock().unwrap();
+
+                for (owner, keys) in by_owner(keys.into_iter().map(|k| (k, ())), ctx.all_node_ids) {
+                    if owner != ctx.node_id {
+                        let keys = keys.into_keys().collect();
+                        forwards.push((owner.to_string(), Payload::ListCommittedOffsets { keys }));
+
+                        continue;
+                    }
+
+                    for key in keys.into_keys() {
+                        if let Some(val) = commit_offsets.get(&key) {
+                            offsets.insert(key, *val);
+                        }
+                    }
+                }
+
+                if forwards.is_empty() {
+                    return Ok(Payload::ListCommittedOffsetsOk { offsets });
+                }
+
+                let network = ctx.network.clone();
+                ctx.defer(async move {
+                    for (owner, request) in forwards {
+                        if let Payload::ListCommittedOffsetsOk { offsets: listed } =
+                            network.forward_rpc(&[owner], request).await?
+                        {
+                            offsets.extend(listed);
+                        }
+                    }
+
+                    Ok(Payload::ListCommittedOffsetsOk { offsets })
+                })
+            }
+
+            #[cfg(feature = "debug_api")]
+            Payload::DebugDump => {
+                let mut message_storage: HashMap<String, Vec<usize>> = self
+                    .replicas
+                    .lock()
+                    .unwrap()
+                    .logs
+                    .iter()
+                    .map(|(key, log)| (key.clone(), log.values().copied().collect()))
+                    .collect();
+
+                let logs: Vec<_> = self
+                    .owned
+                    .iter()
+                    .map(|key| (key.clone(), self.storage.get(key, 0)))
+                    .collect();
+                let commit_offsets = self.commit_offsets.lock().unwrap().clone();
+
+                ctx.defer(async move {
+                    for (key, log) in logs {
+                        let Ok((_, log)) = log.await else {
+                            return Err(NodeError::StorageUnavailable);
+                        };
+
+                        message_storage.insert(key, log);
+                    }
+
+                    Ok(Payload::DebugDumpOk {
+                        message_storage,
+                        commit_offsets,
+                    })
+                })
+            }
+
+            Payload::ReplicaPoll { offsets, max_msgs } => {
+                let limit = max_msgs.unwrap_or(DEFAULT_POLL_LIMIT);
+                let mut messages = BTreeMap::new();
+                let mut truncated = BTreeMap::new();
+
+                let (owned, copies): (BTreeMap<_, _>, BTreeMap<_, _>) = offsets
+                    .into_iter()
+                    .partition(|(key, _)| owner(key, ctx.all_node_ids) == ctx.node_id);
+
+                let replicas = self.replicas.lock().unwrap();
+                for (key, offset) in copies {
+                    if let Some((polled, base)) = replicas.poll(&key, offset, limit) {
+                        if let Some(base) = base {
+                            truncated.insert(key.clone(), base);
+                        }
+                        messages.insert(key, polled);
+                    }
+                }
+
+                let storage = self.storage.clone();
+                ctx.defer(async move {
+                    poll_owned(&storage, &owned, max_msgs, &mut messages, &mut truncated).await?;
+
+                    Ok(Payload::PollOk {
+                        messages,
+                        truncated,
+                    })
+                })
+            }
+
+            Payload::ReplicaAppend { key, msg, offset } => {
+                let mut replicas = self.replicas.lock().unwrap();
+                replicas.copy_entry(&key, offset, msg);
+
+                // The owner compacts at the same point after the same append.
+                if let Some(before) = compaction_point(self.retention, offset) {
+                    replicas.compact(&key, before);
+                }
+
+                Ok(Payload::ReplicaAppendOk)
+            }
+
+            payload => unhandled(&payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Storage, StorageClient, StorageHandle};
+    use crate::tests::{run_to_end, script, sent_messages};
+    use crate::{MaelstromError, NodeBuilder};
+    use std::collections::HashSet;
+    use std::io::Write;
+    use std::time::Instant;
+
+    /// A kafka workload over a fresh in-memory storage service. The handle keeps the
+    /// service's address for tests that look at the logs directly.
+    fn storage_kafka() -> (StorageHandle, Kafka) {
+        let storage = Storage::run_local().unwrap();
+        let kafka = Kafka::new(AsyncStorageClient::new(storage.addr()));
+
+        (storage, kafka)
+    }
+
+    /// The first of `k0`, `k1`, ... that `node` owns among `ids`.
+    fn key_owned_by(node: &str, ids: &[&str]) -> String {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+
+        (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| owner(key, &ids) == node)
+            .unwrap()
+    }
+
+    /// The `init` of n1 in a cluster of `ids`.
+    fn init(ids: &[&str]) -> String {
+        format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"init","msg_id":1,"node_id":"n1","node_ids":{}}}}}"#,
+            serde_json::to_string(ids).unwrap()
+        )
+    }
+
+    #[test]
+    fn nodes_agree_on_key_owner() {
+        let n1_view = vec!["n1".to_string(), "n2".to_string(), "n3".to_string()];
+        let n2_view = vec!["n3".to_string(), "n1".to_string(), "n2".to_string()];
+
+        for key in ["k1", "k2", "k3", "some-longer-key"] {
+            assert_eq!(owner(key, &n1_view), owner(key, &n2_view));
+        }
+    }
+
+    #[test]
+    fn replica_poll_skips_past_holes() {
+        let mut replicas = Replicas::default();
+
+        for offset in [0, 2, 4] {
+            replicas.copy_entry("k1", offset, offset * 10);
+        }
+
+        assert_eq!(replicas.missing_offsets("k1"), vec![1, 3]);
+        assert_eq!(
+            replicas.poll("k1", 1, usize::MAX),
+            Some((
+                vec![
+                    LogEntry {
+                        offset: 2,
+                        value: 20
+                    },
+                    LogEntry {
+                        offset: 4,
+                        value: 40
+                    }
+                ],
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn forward_to_silent_owner_times_out() {
+        let key = key_owned_by("n2", &["n1", "n2"]);
+        let (_storage, kafka) = storage_kafka();
+
+        let input = [
+            init(&["n1", "n2"]),
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"send","msg_id":2,"key":"{key}","msg":10}}}}"#
+            ),
+        ];
+        let sent = run_to_end(
+            NodeBuilder::new(script(&input), Vec::new())
+                .workload(Box::new(kafka))
+                .forward_retries(2, Duration::from_millis(50)),
+        );
+
+        let forwarded = sent
+            .iter()
+            .filter(|message| matches!(message.body.payload, Payload::Send { .. }))
+            .filter(|message| message.dst == "n2")
+            .count();
+        assert_eq!(forwarded, 2);
+
+        let reply = sent.last().unwrap();
+        assert_eq!(reply.dst, "c1");
+        assert!(matches!(
+            reply.body.payload,
+            Payload::Error {
+                code: MaelstromError::Timeout,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn node_answers_others_while_a_forward_is_pending() {
+        let ids = ["n1", "n2"];
+        let (remote, local) = (key_owned_by("n2", &ids), key_owned_by("n1", &ids));
+        let (_storage, kafka) = storage_kafka();
+
+        let input = [
+            init(&ids),
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"send","msg_id":2,"key":"{remote}","msg":10}}}}"#
+            ),
+            format!(
+                r#"{{"src":"c2","dest":"n1","body":{{"type":"list_committed_offsets","msg_id":3,"keys":["{local}"]}}}}"#
+            ),
+        ];
+        let sent = run_to_end(
+            NodeBuilder::new(script(&input), Vec::new())
+                .workload(Box::new(kafka))
+                .forward_retries(1, Duration::from_millis(200)),
+        );
+
+        let replies: Vec<u64> = sent
+            .iter()
+            .filter_map(|message| message.body.in_reply_to.filter(|_| message.dst != "n2"))
+            .collect();
+
+        // The local key is answered while the forwarded send still waits on n2.
+        assert_eq!(replies, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn owner_answers_others_while_waiting_on_replicas() {
+        let ids = ["n1", "n2"];
+        let key = key_owned_by("n1", &ids);
+        let (_storage, kafka) = storage_kafka();
+
+        let input = [
+            init(&ids),
+            format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"send","msg_id":2,"key":"{key}","msg":10}}}}"#
+            ),
+            format!(
+                r#"{{"src":"c2","dest":"n1","body":{{"type":"list_committed_offsets","msg_id":3,"keys":["{key}"]}}}}"#
+            ),
+        ];
+        let sent =
+            run_to_end(NodeBuilder::new(script(&input), Vec::new()).workload(Box::new(kafka)));
+
+        let replies: Vec<u64> = sent
+            .iter()
+            .filter_map(|message| message.body.in_reply_to.filter(|_| message.dst != "n2"))
+            .collect();
+
+        // The send waits on its silent replica n2, the list doesn't wait with it.
+        assert_eq!(replies, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn forwarded_send_ok_replies_to_the_client_request() {
+        let key = key_owned_by("n2", &["n1", "n2"]);
+        let (_storage, kafka) = storage_kafka();
+
+        let (input, mut writer) = std::io::pipe().unwrap();
+        let node = NodeBuilder::new(input, Vec::new())
+            .workload(Box::new(kafka))
+            .build();
+        let output = node.output.clone();
+        let sent = move || -> Vec<Message> { sent_messages(output.lock().unwrap().get_ref()) };
+        let sent_by_node = sent.clone();
+
+        // Plays the client and the owner n2, answering the forwarded send once it shows up.
+        let peer = std::thread::spawn(move || {
+            writeln!(writer, "{}", init(&["n1", "n2"])).unwrap();
+            writeln!(
+                writer,
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"send","msg_id":42,"key":"{key}","msg":10}}}}"#
+            )
+            .unwrap();
+
+            let deadline = Instant::now() + Duration::from_secs(1);
+            let forwarded = loop {
+                let forwarded = sent_by_node().into_iter().find(|message| {
+                    message.dst == "n2" && matches!(message.body.payload, Payload::Send { .. })
+                });
+
+                match forwarded {
+                    Some(forwarded) => break forwarded,
+                    None if Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(5))
+                    }
+                    None => panic!("send was not forwarded"),
+                }
+            };
+
+            writeln!(
+                writer,
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"send_ok","msg_id":1,"in_reply_to":{},"offset":7}}}}"#,
+                forwarded.body.msg_id.unwrap()
+            )
+            .unwrap();
+        });
+
+        node.run();
+        peer.join().unwrap();
+
+        let reply = sent()
+            .into_iter()
+            .find(|message| message.dst == "c1" && message.body.in_reply_to == Some(42))
+            .expect("no reply to the client's send");
+        assert!(matches!(reply.body.payload, Payload::SendOk { offset: 7 }));
+    }
+
+    #[test]
+    fn follower_poll_without_max_msgs_is_capped_like_the_owners() {
+        let key = key_owned_by("n2", &["n1", "n2"]);
+        let (_storage, kafka) = storage_kafka();
+
+        let mut input = vec![init(&["n1", "n2"])];
+        input.extend((0..DEFAULT_POLL_LIMIT + 10).map(|offset| {
+            format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"replica_append","msg_id":{},"key":"{key}","msg":{offset},"offset":{offset}}}}}"#,
+                offset + 1
+            )
+        }));
+        input.push(format!(
+            r#"{{"src":"c1","dest":"n1","body":{{"type":"poll","msg_id":2,"offsets":{{"{key}":0}}}}}}"#
+        ));
+
+        let sent =
+            run_to_end(NodeBuilder::new(script(&input), Vec::new()).workload(Box::new(kafka)));
+
+        let reply = sent
+            .into_iter()
+            .find(|message| message.dst == "c1" && message.body.in_reply_to == Some(2))
+            .expect("no reply to the poll");
+        let Payload::PollOk { messages, .. } = reply.body.payload else {
+            panic!("expected poll_ok");
+        };
+        assert_eq!(messages[&key].len(), DEFAULT_POLL_LIMIT);
+    }
+
+    #[test]
+    fn commit_refused_by_one_owner_is_applied_nowhere() {
+        let ids = ["n1", "n2", "n3"];
+        let [local, accepted, refused] = ids.map(|node| key_owned_by(node, &ids));
+
+        let (storage, kafka) = storage_kafka();
+        StorageClient::connect(storage.addr())
+            .unwrap()
+            .store(&local, &5)
+            .unwrap();
+
+        let (input, mut writer) = std::io::pipe().unwrap();
+        let node = NodeBuilder::new(input, Vec::new())
+            .workload(Box::new(kafka))
+            .build();
+        let output = node.output.clone();
+        let sent = move || -> Vec<Message> { sent_messages(output.lock().unwrap().get_ref()) };
+        let sent_by_node = sent.clone();
+
+        // Plays the client and the owners n2 and n3. n2 accepts its share, n3 refuses it.
+        let peer = std::thread::spawn(move || {
+            let mut answered = HashSet::new();
+            let mut await_reply = |writer: &mut std::io::PipeWriter, msg_id| {
+                let deadline = Instant::now() + Duration::from_secs(2);
+
+                loop {
+                    let sent = sent_by_node();
+                    if sent
+                        .iter()
+                        .any(|message| message.body.in_reply_to == Some(msg_id))
+                    {
+                        return;
+                    }
+                    assert!(Instant::now() < deadline, "request {msg_id} not answered");
+
+                    for request in sent.iter().filter(|message| {
+                        matches!(
+                            message.body.payload,
+                            Payload::CheckCommitOffsets { .. } | Payload::CommitOffsets { .. }
+                        )
+                    }) {
+                        let msg_id = request.body.msg_id.unwrap();
+                        if !answered.insert(msg_id) {
+                            continue;
+                        }
+
+                        let body = match request.dst.as_str() {
+                            "n2" => r#""type":"commit_offsets_ok""#,
+                            _ => r#""type":"error","code":22,"text":"past the log""#,
+                        };
+                        writeln!(
+                            writer,
+                            r#"{{"src":"{}","dest":"n1","body":{{{body},"in_reply_to":{msg_id}}}}}"#,
+                            request.dst
+                        )
+                        .unwrap();
+                    }
+
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            };
+
+            writeln!(writer, "{}", init(&ids)).unwrap();
+            writeln!(
+                writer,
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"commit_offsets","msg_id":42,"offsets":{{"{local}":0,"{accepted}":0,"{refused}":9}}}}}}"#
+            )
+            .unwrap();
+            await_reply(&mut writer, 42);
+
+            writeln!(
+                writer,
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"list_committed_offsets","msg_id":43,"keys":["{local}"]}}}}"#
+            )
+            .unwrap();
+            await_reply(&mut writer, 43);
+        });
+
+        node.run();
+        peer.join().unwrap();
+
+        let sent = sent();
+        let reply = |msg_id| {
+            sent.iter()
+                .find(|message| message.body.in_reply_to == Some(msg_id))
+                .map(|message| message.body.payload.clone())
+                .unwrap()
+        };
+
+        assert!(matches!(
+            reply(42),
+            Payload::Error {
+                code: MaelstromError::PreconditionFailed,
+                ..
+            }
+        ));
+        assert!(!sent
+            .iter()
+            .any(|message| matches!(message.body.payload, Payload::CommitOffsets { .. })));
+        assert!(matches!(
+            reply(43),
+            Payload::ListCommittedOffsetsOk { offsets } if offsets.is_empty()
+        ));
+    }
+
+    #[test]
+    fn stale_follower_forwards_polls_to_the_owner() {
+        let key = key_owned_by("n2", &["n1", "n2"]);
+
+        // Runs n1 as a follower of n2 that got the appends at `offsets`, then polls it.
+        let poll_follower = |offsets: &[usize]| -> Vec<Message> {
+            let (_storage, kafka) = storage_kafka();
+
+            let mut input = vec![init(&["n1", "n2"])];
+            input.extend(offsets.iter().map(|offset| {
+                format!(
+                    r#"{{"src":"n2","dest":"n1","body":{{"type":"replica_append","msg_id":{},"key":"{key}","msg":{},"offset":{offset}}}}}"#,
+                    offset + 1,
+                    offset * 10
+                )
+            }));
+            input.push(format!(
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"poll","msg_id":2,"offsets":{{"{key}":0}}}}}}"#
+            ));
+
+            run_to_end(
+                NodeBuilder::new(script(&input), Vec::new())
+                    .workload(Box::new(kafka.with_max_staleness(2)))
+                    .forward_retries(1, Duration::from_millis(50)),
+            )
+        };
+        let forwarded = |sent: &[Message]| {
+            sent.iter().any(|message| {
+                message.dst == "n2" && matches!(message.body.payload, Payload::Poll { .. })
+            })
+        };
+
+        // Offsets 1 to 4 never arrived, the copy is 5 entries behind offset 5.
+        let stale = poll_follower(&[0, 5]);
+        assert!(forwarded(&stale));
+
+        let fresh = poll_follower(&[0, 1, 2, 3, 4, 5]);
+        assert!(!forwarded(&fresh));
+
+        let reply = fresh
+            .iter()
+            .find(|message| message.dst == "c1" && message.body.in_reply_to == Some(2))
+            .expect("no reply to the poll");
+        let Payload::PollOk { messages, .. } = &reply.body.payload else {
+            panic!("expected poll_ok");
+        };
+        assert_eq!(messages[&key].len(), 6);
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use crate::workload::{unhandled, Workload};
+use crate::{Message, NodeCtx, NodeError, Payload, ReadResult};
+
+/// In-memory key-value store with `read`, `write`, `cas` and `create`, serving `lin-kv`. A
+/// single node handles one request at a time, so every operation is linearizable. Keys and
+/// values are arbitrary JSON.
+#[derive(Default)]
+pub struct KeyValue {
+    store: HashMap<serde_json::Value, serde_json::Value>,
+}
+
+impl Workload for KeyValue {
+    fn name(&self) -> &'static str {
+        "kv"
+    }
+
+    fn handle(&mut self, message: Message, _ctx: &mut NodeCtx) -> Result<Payload, NodeError> {
+        match message.body.payload {
+            Payload::Read { key: Some(key) } => {
+                let Some(value) = self.store.get(&key) else {
+                    return Err(NodeError::KeyDoesNotExist);
+                };
+
+                Ok(Payload::ReadOk(ReadResult::Value {
+                    value: value.clone(),
+                }))
+            }
+
+            Payload::Write { key, value } => {
+                self.store.insert(key, value);
+
+                Ok(Payload::WriteOk)
+            }
+
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                match self.store.get_mut(&key) {
+                    Some(value) if *value == from => *value = to,
+                    Some(_) => return Err(NodeError::PreconditionFailed),
+                    None if create_if_not_exists.unwrap_or(false) => {
+                        self.store.insert(key, to);
+                    }
+                    None => return Err(NodeError::KeyDoesNotExist),
+                }
+
+                Ok(Payload::CasOk)
+            }
+
+            Payload::Create { key, value } => {
+                if self.store.contains_key(&key) {
+                    return Err(NodeError::KeyAlreadyExists);
+                }
+
+                self.store.insert(key, value);
+
+                Ok(Payload::CreateOk)
+            }
+
+            payload => unhandled(&payload),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+
+use crate::workload::{unhandled, Workload};
+use crate::{Message, NodeCtx, NodeError, Payload};
+
+/// Maps node ids to the number of transactions seen from that node. Ordered, so clocks
+/// serialize the same on every node.
+pub type VectorClock = BTreeMap<String, u64>;
+
+/// Whether everything `a` has seen, `b` has seen too.
+fn descends(b: &VectorClock, a: &VectorClock) -> bool {
+    a.iter()
+        .all(|(node, count)| b.get(node).copied().unwrap_or(0) >= *count)
+}
+
+fn merge(into: &mut VectorClock, other: &VectorClock) {
+    for (node, count) in other {
+        let entry = into.entry(node.clone()).or_default();
+        *entry = (*entry).max(*count);
+    }
+}
+
+/// A written value, tagged with the clock of the transaction that wrote it.
+struct Versioned {
+    value: usize,
+    clock: VectorClock,
+}
+
+/// Transactional key-value store. Committed writes are replicated to every peer along with
+/// the committing clock. A replicated write to a key whose stored version has an
+/// incomparable clock is a conflict: of the two, the one from the greater clock, compared
+/// entry by entry, wins on every node. A replicated transaction losing any of its writes is
+/// refused as a whole with `txn-conflict`, leaving the store untouched.
+///
+/// A transaction runs to completion within one call of [`Workload::handle`], and the node
+/// handles one message at a time, so transactions never interleave and need no locks.
+#[derive(Default)]
+pub struct Txn {
+    /// Ordered by key, so dumps of equal stores are equal.
+    store: BTreeMap<usize, Versioned>,
+    clock: VectorClock,
+}
+
+impl Txn {
+    fn replicate(&self, writes: Vec<(usize, usize)>, ctx: &mut NodeCtx) {
+        for peer in ctx.peers() {
+            let _ = ctx.send(
+                peer,
+                Payload::TxnReplicate {
+                    writes: writes.clone(),
+                    clock: self.clock.clone(),
+                },
+            );
+        }
+    }
+
+    /// Whether a replicated write of `key` from `clock` conflicts with the stored version and
+    /// loses to it.
+    fn loses(&self, key: usize, clock: &VectorClock) -> bool {
+        self.store.get(&key).is_some_and(|current| {
+            !descends(&current.clock, clock)
+                && !descends(clock, &current.clock)
+                && current.clock > *clock
+        })
+    }
+
+    /// Applies a replicated write, unless the stored version already saw it.
+    fn apply(&mut self, key: usize, value: usize, clock: &VectorClock) {
+        if self
+            .store
+            .get(&key)
+            .is_some_and(|current| descends(&current.clock, clock))
+        {
+            return;
+        }
+
+        self.store.insert(
+            key,
+            Versioned {
+                value,
+                clock: clock.clone(),
+            },
+        );
+    }
+}
+
+impl Workload for Txn {
+    fn name(&self) -> &'static str {
+        "txn"
+    }
+
+    fn handle(&mut self, message: Message, ctx: &mut NodeCtx) -> Result<Payload, NodeError> {
+        match message.body.payload {
+            Payload::Txn { mut txn } => {
+                // Checked up front, so a malformed transaction changes nothing.
+                let valid = txn.iter().all(|(op, _, value)| {
+                    matches!((op.as_str(), value), ("r", _) | ("w", Some(_)))
+                });
+                if !valid {
+                    return Err(NodeError::IllegalPayload);
+                }
+
+                *self.clock.entry(ctx.node_id.to_string()).or_default() += 1;
+
+                let mut writes = Vec::new();
+
+                for (op, key, value) in &mut txn {
+                    match (op.as_str(), *value) {
+                        ("w", Some(value)) => {
+                            self.store.insert(
+                                *key,
+                                Versioned {
+                                    value,
+                                    clock: self.clock.clone(),
+                                },
+                            );
+                            writes.push((*key, value));
+                        }
+                        _ => *value = self.store.get(key).map(|versioned| versioned.value),
+                    }
+                }
+
+                if !writes.is_empty() {
+                    self.replicate(writes, ctx);
+                }
+
+                Ok(Payload::TxnOk { txn })
+            }
+
+            Payload::TxnReplicate { writes, clock } => {
+                let lost: Vec<usize> = writes
+                    .iter()
+                    .map(|(key, _)| *key)
+                    .filter(|key| self.loses(*key, &clock))
+                    .collect();
+
+                merge(&mut self.clock, &clock);
+
+                if !lost.is_empty() {
+                    tracing::debug!(src = message.src, ?lost, "replicated txn lost a conflict");
+
+                    return Err(NodeError::TxnConflict);
+                }
+
+                for (key, value) in writes {
+                    self.apply(key, value, &clock);
+                }
+
+                Ok(Payload::DontReply)
+            }
+
+            payload => unhandled(&payload),
+        }
+    }
+}